@@ -0,0 +1,1129 @@
+use crate::linux::UInputDevice;
+use crate::uinput::KEY_MAX;
+use mouse_keyboard_input::key_codes::BTN_LEFT;
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+/// A mapping action parsed from its configuration string.
+///
+/// Grammar (key names are case-insensitive, whitespace between tokens is ignored):
+///
+/// ```text
+/// action   := item ("," item)*
+/// item     := (call | chord) ["*" count]
+/// chord    := key ("+" key)* ["+" "[" action "]"] | key "[" action "]"
+/// call     := name "(" [arg ("," arg)*] ")"
+/// key      := name | ("code" | "key") "(" number ")"
+/// ```
+///
+/// `ctrl+alt+del` presses the keys together, `alt[a,f]` and `ctrl+[a,b,c]` hold the keys
+/// while the bracketed actions run, and `a, b` runs actions one after another. `code(164)` and
+/// `key(0x2a4)` stand for keys by their evdev code, `text("...")` types a string,
+/// `exec("...")` and `spawn("...")` start a shell command in the background.
+/// `sleep(150ms)`, `hold(enter, 50ms)`, `delay(30ms, a, b)` and `down*5` control timing and repetition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Actions run one after another
+    Sequence(Vec<Action>),
+    /// Keys pressed together, modifiers first, and released in reverse order
+    Chord(Vec<Key>),
+    /// Keys held down while the inner action runs
+    WithKeys(Vec<Key>, Box<Action>),
+    /// Pointer movement direction, positive y pointing up
+    MouseMove(i32, i32),
+    /// Scroll wheel direction, positive x scrolling right and positive y scrolling up
+    Scroll(i32, i32),
+    /// Switch the active layer, None for the base `mappings`
+    Mode(Option<String>),
+    /// Switch between two layers
+    ModeToggle(Option<String>, Option<String>),
+    /// Use a layer for the next button press only
+    ModeMomentary(Option<String>),
+    /// Use a layer while the button is held, with an action for a release without other presses
+    Shift(String, Option<Box<Action>>),
+    /// Switch T9 text entry
+    T9(Switch),
+    /// Move the pointer to a position given as fractions of the screen from the top left corner
+    PointerTo(f64, f64),
+    /// Switch grid jump mode, where the number buttons pick screen cells
+    Grid(Switch),
+    /// Type a string using the configured keyboard layout
+    Text(String),
+    /// Wait before the next action of a sequence
+    Sleep(Duration),
+    /// Keys held down for a duration instead of a plain click
+    Hold(Vec<Key>, Duration),
+    /// An action run several times
+    Repeat(Box<Action>, u32),
+    /// An action with a pause between its keys, overriding the global key delay
+    Delay(Duration, Box<Action>),
+    /// Keys pressed and left down until released with release(), toggle() or release_all
+    Press(Vec<Key>),
+    /// Keys let go after press() or toggle()
+    Release(Vec<Key>),
+    /// Keys pressed if they are up and released if they were pressed with press() or toggle()
+    Toggle(Vec<Key>),
+    /// Release every key the device holds
+    ReleaseAll,
+    /// Run a shell command and wait for it, or leave it running in the background
+    Exec(ShellCommand),
+    /// Control a media player over MPRIS, optionally naming the player
+    Mpris(MprisCommand, Option<String>),
+    /// Send a command with its arguments to mpv's IPC socket
+    Mpv(Vec<String>),
+    /// Call a Kodi JSON-RPC method with optional parameters
+    Kodi(String, Option<Value>),
+    /// Send an HTTP request in the background
+    Http(HttpRequest),
+}
+
+/// A key, mouse button or modifier with the name it was written as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub name: String,
+    pub code: u16,
+}
+
+/// A command line run with `sh -c` by exec() and spawn()
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellCommand {
+    pub command: String,
+    /// Run the actions after it in a sequence once the command has exited
+    pub wait: bool,
+    /// Kill the command if it is still running after this long
+    pub timeout: Option<Duration>,
+    /// Working directory
+    pub cwd: Option<String>,
+    /// User to run the command as
+    pub user: Option<String>,
+}
+
+/// A request of the http() action; `{button}`, `{duration_ms}` and `{mode}` in the URL and
+/// body are replaced when it is sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    /// GET, POST, PUT, PATCH or DELETE
+    pub method: String,
+    pub url: String,
+    pub body: Option<String>,
+    /// Give up on an attempt after this long
+    pub timeout: Option<Duration>,
+    /// Attempts after the first one when the request fails or the server returns an error
+    pub retries: u32,
+}
+
+/// A media player command of the mpris() action
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MprisCommand {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    /// Seek by a number of microseconds, negative to go back
+    Seek(i64),
+    /// Change the volume by a fraction of full volume, or set it when not relative
+    Volume { value: f64, relative: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Switch {
+    On,
+    Off,
+    Toggle,
+}
+
+impl fmt::Display for Switch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Switch::On => "on",
+            Switch::Off => "off",
+            Switch::Toggle => "toggle",
+        })
+    }
+}
+
+impl fmt::Display for MprisCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MprisCommand::PlayPause => write!(f, "play_pause"),
+            MprisCommand::Play => write!(f, "play"),
+            MprisCommand::Pause => write!(f, "pause"),
+            MprisCommand::Stop => write!(f, "stop"),
+            MprisCommand::Next => write!(f, "next"),
+            MprisCommand::Previous => write!(f, "previous"),
+            MprisCommand::Seek(us) => write!(f, "seek, {:+}ms", us / 1000),
+            MprisCommand::Volume { value, relative: true } => write!(f, "volume, {:+}%", value * 100.0),
+            MprisCommand::Volume { value, relative: false } => write!(f, "volume, {}%", value * 100.0),
+        }
+    }
+}
+
+/// Error in an action string, with the 1-based column it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Name of the `mappings` layer in mode actions
+pub const BASE_LAYER: &str = "base";
+
+/// Longest sleep(), hold() or delay() duration; the input thread cannot send anything else meanwhile
+pub const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Most retries of an http() request; later requests wait on the same thread meanwhile
+pub const MAX_RETRIES: u32 = 10;
+
+impl Action {
+    pub fn parse(input: &str) -> Result<Action, ParseError> {
+        Self::parse_at(input, 0)
+    }
+
+    // Parse a sub-string that starts `offset` characters into the mapping, for error columns
+    fn parse_at(input: &str, offset: usize) -> Result<Action, ParseError> {
+        let mut parser = Parser { chars: input.chars().collect(), pos: 0, offset };
+        let action = parser.sequence(None)?;
+        parser.skip_ws();
+        if let Some(c) = parser.peek() {
+            return Err(parser.error(format!("unexpected '{}'", c)));
+        }
+        Ok(action)
+    }
+
+    /// Keys a hold mode press keeps down until the button is released, None for actions sent as a click
+    pub fn held_keys(&self) -> Option<&[Key]> {
+        match self {
+            Action::Chord(keys) => Some(keys),
+            _ => None,
+        }
+    }
+
+    /// Keys this action itself presses, not counting nested actions
+    pub fn keys(&self) -> &[Key] {
+        match self {
+            Action::Chord(keys) | Action::WithKeys(keys, _) | Action::Hold(keys, _) => keys,
+            Action::Press(keys) | Action::Release(keys) | Action::Toggle(keys) => keys,
+            _ => &[],
+        }
+    }
+
+    /// Mode, shift, T9 and grid actions, which change what the buttons do instead of sending input
+    pub fn is_control(&self) -> bool {
+        matches!(self,
+            Action::Mode(_) | Action::ModeToggle(..) | Action::ModeMomentary(_) |
+            Action::Shift(..) | Action::T9(_) | Action::Grid(_))
+    }
+
+    /// Call `f` for this action and every action nested in it
+    pub fn walk(&self, f: &mut impl FnMut(&Action)) {
+        f(self);
+        match self {
+            Action::Sequence(items) => items.iter().for_each(|a| a.walk(f)),
+            Action::WithKeys(_, inner) | Action::Repeat(inner, _) | Action::Delay(_, inner) => inner.walk(f),
+            Action::Shift(_, Some(fallback)) => fallback.walk(f),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let layer = |l: &Option<String>| l.clone().unwrap_or_else(|| BASE_LAYER.to_string());
+        match self {
+            Action::Sequence(items) => {
+                let items: Vec<String> = items.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}", items.join(", "))
+            }
+            Action::Chord(keys) => write!(f, "{}", key_names(keys)),
+            Action::WithKeys(keys, inner) => write!(f, "{}+[{}]", key_names(keys), inner),
+            Action::MouseMove(x, y) => {
+                let vertical = match y.signum() {
+                    1 => "_up",
+                    -1 => "_down",
+                    _ => "",
+                };
+                let horizontal = match x.signum() {
+                    1 => "_right",
+                    -1 => "_left",
+                    _ => "",
+                };
+                write!(f, "mouse{}{}", vertical, horizontal)
+            }
+            Action::Scroll(x, y) => {
+                let name = match (x.signum(), y.signum()) {
+                    (1, _) => "scroll_right",
+                    (-1, _) => "scroll_left",
+                    (_, 1) => "scroll_up",
+                    _ => "scroll_down",
+                };
+                write!(f, "{}", name)
+            }
+            Action::Mode(l) => write!(f, "mode({})", layer(l)),
+            Action::ModeToggle(a, b) => write!(f, "mode_toggle({},{})", layer(a), layer(b)),
+            Action::ModeMomentary(l) => write!(f, "mode_momentary({})", layer(l)),
+            Action::Shift(l, None) => write!(f, "shift({})", l),
+            Action::Shift(l, Some(fallback)) => write!(f, "shift({}, {})", l, fallback),
+            Action::T9(switch) => write!(f, "t9({})", switch),
+            Action::PointerTo(x, y) => write!(f, "pointer_to({}%, {}%)", x * 100.0, y * 100.0),
+            Action::Grid(switch) => write!(f, "grid({})", switch),
+            Action::Text(text) => write!(f, "text({:?})", text),
+            Action::Sleep(duration) => write!(f, "sleep({}ms)", duration.as_millis()),
+            Action::Hold(keys, duration) => write!(f, "hold({}, {}ms)", key_names(keys), duration.as_millis()),
+            Action::Repeat(inner, count) => write!(f, "{}*{}", inner, count),
+            Action::Delay(duration, inner) => write!(f, "delay({}ms, {})", duration.as_millis(), inner),
+            Action::Press(keys) => write!(f, "press({})", key_names(keys)),
+            Action::Release(keys) => write!(f, "release({})", key_names(keys)),
+            Action::Toggle(keys) => write!(f, "toggle({})", key_names(keys)),
+            Action::ReleaseAll => write!(f, "release_all"),
+            Action::Exec(exec) => {
+                write!(f, "exec({:?}", exec.command)?;
+                if let Some(timeout) = exec.timeout {
+                    write!(f, ", timeout={}ms", timeout.as_millis())?;
+                }
+                if let Some(cwd) = &exec.cwd {
+                    write!(f, ", cwd={:?}", cwd)?;
+                }
+                if let Some(user) = &exec.user {
+                    write!(f, ", user={}", user)?;
+                }
+                if exec.wait {
+                    write!(f, ", wait")?;
+                }
+                write!(f, ")")
+            }
+            Action::Mpris(command, None) => write!(f, "mpris({})", command),
+            Action::Mpris(command, Some(player)) => write!(f, "mpris({}, player={})", command, player),
+            Action::Mpv(words) => {
+                let words: Vec<String> = words.iter()
+                    .map(|w| if w.is_empty() || w.contains(char::is_whitespace) { format!("{:?}", w) } else { w.clone() })
+                    .collect();
+                write!(f, "mpv({})", words.join(" "))
+            }
+            Action::Kodi(method, None) => write!(f, "kodi({})", method),
+            Action::Kodi(method, Some(params)) => write!(f, "kodi({}, {})", method, params),
+            Action::Http(request) => {
+                write!(f, "http({}, {:?}", request.method, request.url)?;
+                if let Some(body) = &request.body {
+                    write!(f, ", {:?}", body)?;
+                }
+                if let Some(timeout) = request.timeout {
+                    write!(f, ", timeout={}ms", timeout.as_millis())?;
+                }
+                if request.retries > 0 {
+                    write!(f, ", retries={}", request.retries)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn key_names(keys: &[Key]) -> String {
+    keys.iter().map(|k| k.name.as_str()).collect::<Vec<_>>().join("+")
+}
+
+// A raw call argument and the column it starts at
+struct Arg {
+    text: String,
+    column: usize,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    offset: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn column(&self) -> usize {
+        self.offset + self.pos + 1
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError { column: self.column(), message }
+    }
+
+    fn column_at(&self, pos: usize) -> usize {
+        self.offset + pos + 1
+    }
+
+    fn error_at(&self, pos: usize, message: String) -> ParseError {
+        ParseError { column: self.column_at(pos), message }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        self.skip_ws();
+        if self.peek() != Some(c) {
+            let found = self.peek().map(|f| format!("'{}'", f)).unwrap_or_else(|| "end of action".to_string());
+            return Err(self.error(format!("expected '{}', found {}", c, found)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Comma separated items, up to `end` (not consumed) or the end of input
+    fn sequence(&mut self, end: Option<char>) -> Result<Action, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_ws();
+            let start = self.pos;
+            let item = self.item()?;
+            items.push(self.repeat(start, item)?);
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                None => break,
+                Some(c) if Some(c) == end => break,
+                Some(c) => return Err(self.error(format!("unexpected '{}', expected ',' or end of action", c))),
+            }
+        }
+        Ok(if items.len() == 1 { items.remove(0) } else { Action::Sequence(items) })
+    }
+
+    // Optional `*count` after an item starting at `item_start`
+    fn repeat(&mut self, item_start: usize, item: Action) -> Result<Action, ParseError> {
+        self.skip_ws();
+        if self.peek() != Some('*') {
+            return Ok(item);
+        }
+        self.pos += 1;
+        self.skip_ws();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        match digits.parse::<u32>() {
+            Ok(count) if count > 0 => {
+                no_control(&item, self.column_at(item_start), "*N")?;
+                Ok(Action::Repeat(Box::new(item), count))
+            }
+            _ => Err(self.error_at(start, "expected a repeat count after '*'".to_string())),
+        }
+    }
+
+    // The rest of a call's arguments from `first` on, parsed as one action that may contain commas
+    fn rest_action(&self, first: &Arg) -> Result<Action, ParseError> {
+        let from = first.column - 1 - self.offset;
+        let text: String = self.chars[from..self.pos - 1].iter().collect();
+        Action::parse_at(&text, first.column - 1)
+    }
+
+    fn ident(&mut self) -> (usize, String) {
+        self.skip_ws();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        (start, self.chars[start..self.pos].iter().collect())
+    }
+
+    fn item(&mut self) -> Result<Action, ParseError> {
+        let (start, name) = self.ident();
+        if name.is_empty() {
+            return Err(self.error(match self.peek() {
+                Some(c) => format!("unexpected '{}', expected a key or action", c),
+                None => "expected a key or action".to_string(),
+            }));
+        }
+
+        self.skip_ws();
+        match self.peek() {
+            Some('(') if !is_raw_key(&name) => return self.call(start, &name),
+            Some('[') => {
+                let keys = vec![self.key(start, &name)?];
+                return self.with_keys(keys);
+            }
+            _ => {}
+        }
+
+        // A lone mouse movement or scroll token, or a named action without arguments
+        if self.peek() != Some('+') {
+            let left = || vec![Key { name: "mouse_click_left".to_string(), code: BTN_LEFT }];
+            match name.to_lowercase().as_str() {
+                "release_all" => return Ok(Action::ReleaseAll),
+                "mouse_double_click" => return Ok(Action::Repeat(Box::new(Action::Chord(left())), 2)),
+                "mouse_drag_toggle" => return Ok(Action::Toggle(left())),
+                _ => {}
+            }
+            if let Some((x, y)) = mouse_direction(&name) {
+                return Ok(Action::MouseMove(x, y));
+            }
+            if let Some((x, y)) = scroll_direction(&name) {
+                return Ok(Action::Scroll(x, y));
+            }
+        }
+
+        let mut keys = vec![self.key(start, &name)?];
+        while self.peek() == Some('+') {
+            self.pos += 1;
+            self.skip_ws();
+            if self.peek() == Some('[') {
+                return self.with_keys(keys);
+            }
+            let (start, name) = self.ident();
+            if name.is_empty() {
+                return Err(self.error("expected a key after '+'".to_string()));
+            }
+            keys.push(self.key(start, &name)?);
+            self.skip_ws();
+        }
+
+        Ok(Action::Chord(modifiers_first(keys)))
+    }
+
+    // `[` action `]` held under `keys`
+    fn with_keys(&mut self, keys: Vec<Key>) -> Result<Action, ParseError> {
+        self.expect('[')?;
+        let column = self.column();
+        let inner = self.sequence(Some(']'))?;
+        no_control(&inner, column, "held keys")?;
+        self.expect(']')?;
+        Ok(Action::WithKeys(modifiers_first(keys), Box::new(inner)))
+    }
+
+    fn key(&mut self, start: usize, name: &str) -> Result<Key, ParseError> {
+        if is_raw_key(name) && self.peek() == Some('(') {
+            return self.raw_key(start, name);
+        }
+        let name = name.to_lowercase();
+        match UInputDevice::key_code(&name) {
+            Some(code) => Ok(Key { name, code }),
+            None if mouse_direction(&name).is_some() => {
+                Err(self.error_at(start, format!("mouse movement '{}' cannot be combined with keys", name)))
+            }
+            None if scroll_direction(&name).is_some() => {
+                Err(self.error_at(start, format!("scrolling '{}' cannot be combined with keys", name)))
+            }
+            None => Err(self.error_at(start, format!("unknown key '{}'", name))),
+        }
+    }
+
+    // code(164) or key(0x2a4), an evdev code without a name
+    fn raw_key(&mut self, start: usize, name: &str) -> Result<Key, ParseError> {
+        let args = self.args()?;
+        let [arg] = args.as_slice() else {
+            return Err(self.error_at(start, format!("{}() takes 1 argument(s), got {}", name, args.len())));
+        };
+        let text = arg.text.to_lowercase();
+        let code = match text.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => text.parse(),
+        };
+        match code {
+            Ok(code) if (1..=KEY_MAX).contains(&code) => Ok(Key { name: format!("{}({})", name.to_lowercase(), text), code }),
+            _ => Err(ParseError {
+                column: arg.column,
+                message: format!("invalid key code '{}', expected 1 to {} (0x{:x})", arg.text, KEY_MAX, KEY_MAX),
+            }),
+        }
+    }
+
+    fn call(&mut self, start: usize, name: &str) -> Result<Action, ParseError> {
+        let args = self.args()?;
+        let arity = |min: usize, max: usize| -> Result<(), ParseError> {
+            if args.len() < min || args.len() > max {
+                let expected = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+                return Err(self.error_at(start, format!("{}() takes {} argument(s), got {}", name, expected, args.len())));
+            }
+            Ok(())
+        };
+        let layer = |i: usize| -> Option<String> {
+            args.get(i).map(|a| a.text.clone()).filter(|l| l != BASE_LAYER)
+        };
+
+        match name.to_lowercase().as_str() {
+            "mode" => {
+                arity(1, 1)?;
+                Ok(Action::Mode(layer(0)))
+            }
+            "mode_toggle" => {
+                arity(1, 2)?;
+                Ok(Action::ModeToggle(layer(0), layer(1)))
+            }
+            "mode_momentary" => {
+                arity(1, 1)?;
+                Ok(Action::ModeMomentary(layer(0)))
+            }
+            "shift" => {
+                if args.is_empty() {
+                    arity(1, 2)?;
+                }
+                // Everything after the layer is the fallback action, which may contain commas itself
+                let fallback = match args.get(1) {
+                    Some(first) => Some(Box::new(self.rest_action(first)?)),
+                    None => None,
+                };
+                Ok(Action::Shift(args[0].text.clone(), fallback))
+            }
+            "t9" => {
+                arity(0, 1)?;
+                Ok(Action::T9(switch(args.first())?))
+            }
+            "grid" => {
+                arity(0, 1)?;
+                Ok(Action::Grid(switch(args.first())?))
+            }
+            "pointer_to" => {
+                arity(2, 2)?;
+                Ok(Action::PointerTo(percent(&args[0])?, percent(&args[1])?))
+            }
+            "text" => {
+                arity(1, 1)?;
+                Ok(Action::Text(unquote(&args[0])?))
+            }
+            "sleep" => {
+                arity(1, 1)?;
+                Ok(Action::Sleep(wait(&args[0])?))
+            }
+            "hold" => {
+                arity(2, 2)?;
+                Ok(Action::Hold(chord(name, &args[0])?, wait(&args[1])?))
+            }
+            "press" => {
+                arity(1, 1)?;
+                Ok(Action::Press(chord(name, &args[0])?))
+            }
+            "release" => {
+                arity(1, 1)?;
+                Ok(Action::Release(chord(name, &args[0])?))
+            }
+            "toggle" => {
+                arity(1, 1)?;
+                Ok(Action::Toggle(chord(name, &args[0])?))
+            }
+            "release_all" => {
+                arity(0, 0)?;
+                Ok(Action::ReleaseAll)
+            }
+            "exec" | "spawn" => {
+                if args.is_empty() {
+                    arity(1, 5)?;
+                }
+                let mut exec = ShellCommand { command: unquote(&args[0])?, wait: false, timeout: None, cwd: None, user: None };
+                for arg in &args[1..] {
+                    if arg.text.eq_ignore_ascii_case("wait") {
+                        if name.eq_ignore_ascii_case("spawn") {
+                            let message = "spawn() does not wait, use exec(..., wait)".to_string();
+                            return Err(ParseError { column: arg.column, message });
+                        }
+                        exec.wait = true;
+                        continue;
+                    }
+                    let Some((key, value)) = arg.text.split_once('=') else {
+                        let message = format!("expected timeout=, cwd=, user= or wait, got '{}'", arg.text);
+                        return Err(ParseError { column: arg.column, message });
+                    };
+                    let value = Arg { text: value.trim().to_string(), column: arg.column + key.chars().count() + 1 };
+                    match key.trim().to_lowercase().as_str() {
+                        "timeout" => exec.timeout = Some(duration(&value)?),
+                        "cwd" => exec.cwd = Some(unquote(&value).unwrap_or(value.text)),
+                        "user" => exec.user = Some(unquote(&value).unwrap_or(value.text)),
+                        other => {
+                            let message = format!("unknown option '{}', expected timeout, cwd, user or wait", other);
+                            return Err(ParseError { column: arg.column, message });
+                        }
+                    }
+                }
+                Ok(Action::Exec(exec))
+            }
+            "mpris" => {
+                // An optional player=name comes last
+                let (player, args) = match args.split_last() {
+                    Some((last, rest)) if last.text.to_lowercase().starts_with("player=") => {
+                        (Some(last.text["player=".len()..].trim().to_string()), rest)
+                    }
+                    _ => (None, args.as_slice()),
+                };
+                let Some(first) = args.first() else {
+                    return Err(self.error_at(start, "mpris() takes a command".to_string()));
+                };
+                let simple = |command| if args.len() == 1 {
+                    Ok(command)
+                } else {
+                    Err(ParseError { column: args[1].column, message: format!("mpris({}) takes no value", first.text) })
+                };
+                let value = || match args {
+                    [_, value] => Ok(value),
+                    _ => Err(ParseError {
+                        column: first.column,
+                        message: format!("mpris({}) takes one value, e.g. +30s or +5%", first.text),
+                    }),
+                };
+                let command = match first.text.to_lowercase().as_str() {
+                    "play_pause" | "playpause" => simple(MprisCommand::PlayPause)?,
+                    "play" => simple(MprisCommand::Play)?,
+                    "pause" => simple(MprisCommand::Pause)?,
+                    "stop" => simple(MprisCommand::Stop)?,
+                    "next" => simple(MprisCommand::Next)?,
+                    "previous" | "prev" => simple(MprisCommand::Previous)?,
+                    "seek" => MprisCommand::Seek(offset(value()?)?),
+                    "volume" => {
+                        let arg = value()?;
+                        let relative = arg.text.starts_with(['+', '-']);
+                        let value = signed_percent(arg)?;
+                        MprisCommand::Volume { value, relative }
+                    }
+                    other => {
+                        let message = format!(
+                            "unknown mpris command '{}', expected play_pause, play, pause, stop, next, previous, seek or volume",
+                            other);
+                        return Err(ParseError { column: first.column, message });
+                    }
+                };
+                Ok(Action::Mpris(command, player))
+            }
+            "mpv" => {
+                if args.is_empty() {
+                    return Err(self.error_at(start, "mpv() takes a command".to_string()));
+                }
+                let mut words = Vec::new();
+                for arg in &args {
+                    words.extend(command_words(arg)?);
+                }
+                Ok(Action::Mpv(words))
+            }
+            "kodi" => {
+                arity(1, 2)?;
+                let method = &args[0];
+                let valid = method.text.split_once('.').is_some_and(|(namespace, name)| {
+                    [namespace, name].iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+                });
+                if !valid {
+                    let message = format!("invalid Kodi method '{}', expected e.g. Input.Select", method.text);
+                    return Err(ParseError { column: method.column, message });
+                }
+                let params = match args.get(1) {
+                    Some(arg) => Some(json_params(arg)?),
+                    None => None,
+                };
+                Ok(Action::Kodi(method.text.clone(), params))
+            }
+            "http" => {
+                if args.len() < 2 {
+                    arity(2, 5)?;
+                }
+                let method = args[0].text.to_uppercase();
+                if !["GET", "POST", "PUT", "PATCH", "DELETE"].contains(&method.as_str()) {
+                    let message = format!("unknown HTTP method '{}', expected GET, POST, PUT, PATCH or DELETE", args[0].text);
+                    return Err(ParseError { column: args[0].column, message });
+                }
+                let url = unquote(&args[1]).unwrap_or_else(|_| args[1].text.clone());
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    let message = format!("invalid URL '{}', expected http://... or https://...", url);
+                    return Err(ParseError { column: args[1].column, message });
+                }
+                let mut request = HttpRequest { method, url, body: None, timeout: None, retries: 0 };
+                for arg in &args[2..] {
+                    // timeout= and retries= are options, anything else is the body
+                    let option = arg.text.split_once('=')
+                        .map(|(key, value)| (key.trim().to_lowercase(), value))
+                        .filter(|(key, _)| key == "timeout" || key == "retries");
+                    match option {
+                        Some((key, value)) => {
+                            let value = Arg { text: value.trim().to_string(), column: arg.column + key.chars().count() + 1 };
+                            if key == "timeout" {
+                                request.timeout = Some(duration(&value)?);
+                            } else {
+                                request.retries = match value.text.parse() {
+                                    Ok(retries) if retries <= MAX_RETRIES => retries,
+                                    _ => return Err(ParseError {
+                                        column: value.column,
+                                        message: format!("invalid retry count '{}', expected 0 to {}", value.text, MAX_RETRIES),
+                                    }),
+                                };
+                            }
+                        }
+                        None if request.body.is_none() => {
+                            request.body = Some(unquote(arg).unwrap_or_else(|_| arg.text.clone()));
+                        }
+                        None => {
+                            let message = format!("expected timeout= or retries=, got '{}'", arg.text);
+                            return Err(ParseError { column: arg.column, message });
+                        }
+                    }
+                }
+                Ok(Action::Http(request))
+            }
+            "delay" => {
+                if args.len() < 2 {
+                    arity(2, 2)?;
+                }
+                let inner = self.rest_action(&args[1])?;
+                no_control(&inner, args[1].column, "delay()")?;
+                Ok(Action::Delay(wait(&args[0])?, Box::new(inner)))
+            }
+            _ => Err(self.error_at(start, format!("unknown action '{}'", name))),
+        }
+    }
+
+    // `(` arg, ... `)` with arguments split on top-level commas; quotes, brackets and braces may nest
+    fn args(&mut self) -> Result<Vec<Arg>, ParseError> {
+        self.expect('(')?;
+        let open = self.pos - 1;
+        let mut args = Vec::new();
+        let mut depth = 0;
+        let mut quote: Option<char> = None;
+        let mut start = self.pos;
+
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error_at(open, "unclosed '('".to_string()));
+            };
+            match (quote, c) {
+                (Some(q), '\\') if self.chars.get(self.pos + 1).is_some() && q == '"' => self.pos += 1,
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (None, '(') | (None, '[') | (None, '{') => depth += 1,
+                (None, ')') | (None, ']') | (None, '}') if depth > 0 => depth -= 1,
+                (None, ',') | (None, ')') if depth == 0 => {
+                    let raw: String = self.chars[start..self.pos].iter().collect();
+                    let leading = raw.len() - raw.trim_start().len();
+                    let text = raw.trim().to_string();
+                    let column = self.offset + start + raw[..leading].chars().count() + 1;
+                    if !text.is_empty() || c == ',' || !args.is_empty() {
+                        args.push(Arg { text, column });
+                    }
+                    if c == ')' {
+                        self.pos += 1;
+                        break;
+                    }
+                    start = self.pos + 1;
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+
+        if let Some(empty) = args.iter().find(|a| a.text.is_empty()) {
+            return Err(ParseError { column: empty.column, message: "empty argument".to_string() });
+        }
+        Ok(args)
+    }
+}
+
+// Control actions change state when the button is handled, before any input is sent, and
+// exec(..., wait) splits its sequence, so neither can be repeated, delayed or run under held keys
+fn no_control(inner: &Action, column: usize, context: &str) -> Result<(), ParseError> {
+    let mut control = None;
+    inner.walk(&mut |a| {
+        let waits = matches!(a, Action::Exec(exec) if exec.wait);
+        if (a.is_control() || waits) && control.is_none() {
+            control = Some(a.to_string());
+        }
+    });
+    match control {
+        Some(action) => Err(ParseError { column, message: format!("{} cannot be used inside {}", action, context) }),
+        None => Ok(()),
+    }
+}
+
+// A key or chord argument such as shift or ctrl+alt
+fn chord(name: &str, arg: &Arg) -> Result<Vec<Key>, ParseError> {
+    match Action::parse_at(&arg.text, arg.column - 1)? {
+        Action::Chord(keys) => Ok(keys),
+        _ => Err(ParseError { column: arg.column, message: format!("{}() takes a key or chord", name) }),
+    }
+}
+
+// A duration argument such as 150ms, 1.5s or 150 (milliseconds)
+fn duration(arg: &Arg) -> Result<Duration, ParseError> {
+    let text = arg.text.to_lowercase();
+    let parsed = if let Some(ms) = text.strip_suffix("ms") {
+        ms.trim().parse().ok().map(Duration::from_millis)
+    } else if let Some(secs) = text.strip_suffix('s') {
+        secs.trim().parse().ok().and_then(|s: f64| Duration::try_from_secs_f64(s).ok())
+    } else {
+        text.parse().ok().map(Duration::from_millis)
+    };
+    parsed.ok_or_else(|| ParseError {
+        column: arg.column,
+        message: format!("invalid duration '{}', expected e.g. 150ms or 1.5s", arg.text),
+    })
+}
+
+// A sleep(), hold() or delay() duration, at most MAX_WAIT
+fn wait(arg: &Arg) -> Result<Duration, ParseError> {
+    let duration = duration(arg)?;
+    if duration > MAX_WAIT {
+        return Err(ParseError {
+            column: arg.column,
+            message: format!("duration '{}' is too long, the maximum is {}s", arg.text, MAX_WAIT.as_secs()),
+        });
+    }
+    Ok(duration)
+}
+
+// on, off or toggle, toggle when left out
+fn switch(arg: Option<&Arg>) -> Result<Switch, ParseError> {
+    match arg.map(|a| a.text.to_lowercase()).as_deref() {
+        None | Some("toggle") => Ok(Switch::Toggle),
+        Some("on") => Ok(Switch::On),
+        Some("off") => Ok(Switch::Off),
+        Some(other) => Err(ParseError {
+            column: arg.map_or(0, |a| a.column),
+            message: format!("expected on, off or toggle, got '{}'", other),
+        }),
+    }
+}
+
+// A screen position from 0% to 100%, the percent sign being optional, as a fraction
+fn percent(arg: &Arg) -> Result<f64, ParseError> {
+    let text = arg.text.strip_suffix('%').unwrap_or(&arg.text).trim();
+    match text.parse::<f64>() {
+        Ok(value) if (0.0..=100.0).contains(&value) => Ok(value / 100.0),
+        _ => Err(ParseError {
+            column: arg.column,
+            message: format!("invalid position '{}', expected 0% to 100%", arg.text),
+        }),
+    }
+}
+
+// A signed duration such as +30s or -10s, in microseconds
+fn offset(arg: &Arg) -> Result<i64, ParseError> {
+    let (sign, text) = match arg.text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, arg.text.strip_prefix('+').unwrap_or(&arg.text)),
+    };
+    let magnitude = duration(&Arg { text: text.to_string(), column: arg.column })?;
+    Ok(sign * magnitude.as_micros() as i64)
+}
+
+// A percentage such as +5%, -5% or 50%, as a fraction
+fn signed_percent(arg: &Arg) -> Result<f64, ParseError> {
+    let text = arg.text.strip_suffix('%').unwrap_or(&arg.text).trim();
+    match text.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value / 100.0),
+        _ => Err(ParseError { column: arg.column, message: format!("invalid percentage '{}'", arg.text) }),
+    }
+}
+
+// JSON-RPC parameters, an object such as {"action": "back"} or an array
+fn json_params(arg: &Arg) -> Result<Value, ParseError> {
+    match serde_json::from_str::<Value>(&arg.text) {
+        Ok(params) if params.is_object() || params.is_array() => Ok(params),
+        Ok(_) => Err(ParseError { column: arg.column, message: "expected a JSON object or array".to_string() }),
+        Err(e) => {
+            // The column is reported separately, drop serde_json's " at line 1 column N"
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or_default();
+            Err(ParseError {
+                column: arg.column + if e.line() == 1 { e.column().saturating_sub(1) } else { 0 },
+                message: format!("invalid JSON parameters: {}", message),
+            })
+        }
+    }
+}
+
+// Words of a command such as `seek 10` or `loadfile "/media/my video.mp4"`, split on whitespace
+// outside quotes. A single argument quoted as a whole is unquoted first.
+fn command_words(arg: &Arg) -> Result<Vec<String>, ParseError> {
+    let text = unquote(arg).unwrap_or_else(|_| arg.text.clone());
+    let mut words = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        let mut quote = None;
+        while let Some(&c) = chars.peek() {
+            match (quote, c) {
+                (None, c) if c.is_whitespace() => break,
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (Some('"'), '\\') => {
+                    chars.next();
+                    word.extend(chars.peek());
+                }
+                _ => word.push(c),
+            }
+            chars.next();
+        }
+        if quote.is_some() {
+            return Err(ParseError { column: arg.column, message: "unclosed quote in command".to_string() });
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// A string argument in double quotes, with \\, \", \n and \t escapes, or in single quotes without escapes
+fn unquote(arg: &Arg) -> Result<String, ParseError> {
+    let text = &arg.text;
+    let quote = text.chars().next().filter(|q| *q == '"' || *q == '\'');
+    let Some(inner) = quote.and_then(|q| text.strip_prefix(q)?.strip_suffix(q)) else {
+        return Err(ParseError { column: arg.column, message: "expected a quoted string".to_string() });
+    };
+    if quote == Some('\'') {
+        return Ok(inner.to_string());
+    }
+
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c @ ('\\' | '"')) => out.push(c),
+            other => {
+                let escape = other.map(|c| format!("\\{}", c)).unwrap_or_else(|| "\\".to_string());
+                return Err(ParseError { column: arg.column, message: format!("unknown escape '{}'", escape) });
+            }
+        }
+    }
+    Ok(out)
+}
+
+// code(...) and key(...) take a numeric evdev code and can be used like key names
+fn is_raw_key(name: &str) -> bool {
+    name.eq_ignore_ascii_case("code") || name.eq_ignore_ascii_case("key")
+}
+
+// Unit vector for a mouse movement token, positive y pointing up
+fn mouse_direction(name: &str) -> Option<(i32, i32)> {
+    match name.to_lowercase().as_str() {
+        "mouse_right" => Some((1, 0)),
+        "mouse_left" => Some((-1, 0)),
+        "mouse_up" => Some((0, 1)),
+        "mouse_down" => Some((0, -1)),
+        "mouse_up_right" | "mouse_right_up" => Some((1, 1)),
+        "mouse_up_left" | "mouse_left_up" => Some((-1, 1)),
+        "mouse_down_right" | "mouse_right_down" => Some((1, -1)),
+        "mouse_down_left" | "mouse_left_down" => Some((-1, -1)),
+        _ => None,
+    }
+}
+
+// Unit vector for a scroll token, positive y scrolling up
+fn scroll_direction(name: &str) -> Option<(i32, i32)> {
+    match name.to_lowercase().as_str() {
+        "scroll_right" => Some((1, 0)),
+        "scroll_left" => Some((-1, 0)),
+        "scroll_up" => Some((0, 1)),
+        "scroll_down" => Some((0, -1)),
+        _ => None,
+    }
+}
+
+fn modifiers_first(mut keys: Vec<Key>) -> Vec<Key> {
+    keys.sort_by_key(|k| !UInputDevice::is_modifier(k.code));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<Key> {
+        names.iter().map(|n| Key { name: n.to_string(), code: UInputDevice::key_code(n).unwrap() }).collect()
+    }
+
+    fn error(input: &str) -> (usize, String) {
+        let e = Action::parse(input).unwrap_err();
+        (e.column, e.message)
+    }
+
+    #[test]
+    fn grammar() {
+        assert_eq!(Action::parse("a+ctrl").unwrap(), Action::Chord(keys(&["ctrl", "a"])));
+        assert_eq!(Action::parse("ctrl+[a, b]").unwrap(), Action::WithKeys(
+            keys(&["ctrl"]),
+            Box::new(Action::Sequence(vec![Action::Chord(keys(&["a"])), Action::Chord(keys(&["b"]))])),
+        ));
+        assert_eq!(Action::parse("down*3, mouse_up_left").unwrap(), Action::Sequence(vec![
+            Action::Repeat(Box::new(Action::Chord(keys(&["down"]))), 3),
+            Action::MouseMove(-1, 1),
+        ]));
+        assert_eq!(Action::parse("delay(30ms, a, b)").unwrap(), Action::Delay(
+            Duration::from_millis(30),
+            Box::new(Action::Sequence(vec![Action::Chord(keys(&["a"])), Action::Chord(keys(&["b"]))])),
+        ));
+        assert_eq!(Action::parse("shift(nav, esc, b)").unwrap(), Action::Shift(
+            "nav".to_string(),
+            Some(Box::new(Action::Sequence(vec![Action::Chord(keys(&["esc"])), Action::Chord(keys(&["b"]))]))),
+        ));
+        assert_eq!(Action::parse("mode(base)").unwrap(), Action::Mode(None));
+        assert_eq!(Action::parse("code(0x2a4)").unwrap(), Action::Chord(vec![Key { name: "code(0x2a4)".to_string(), code: 0x2a4 }]));
+        assert_eq!(Action::parse("text(\"a\\\"b\\n\")").unwrap(), Action::Text("a\"b\n".to_string()));
+        assert_eq!(Action::parse("spawn('kodi', timeout=1.5s, user=media)").unwrap(), Action::Exec(ShellCommand {
+            command: "kodi".to_string(),
+            wait: false,
+            timeout: Some(Duration::from_millis(1500)),
+            cwd: None,
+            user: Some("media".to_string()),
+        }));
+        assert_eq!(Action::parse("mpris(seek, -10s, player=vlc)").unwrap(),
+            Action::Mpris(MprisCommand::Seek(-10_000_000), Some("vlc".to_string())));
+        assert_eq!(Action::parse("mpv(\"loadfile '/media/my video.mp4'\")").unwrap(),
+            Action::Mpv(vec!["loadfile".to_string(), "/media/my video.mp4".to_string()]));
+        assert_eq!(Action::parse("kodi(Input.ExecuteAction, {\"action\": \"back\"})").unwrap(),
+            Action::Kodi("Input.ExecuteAction".to_string(), Some(serde_json::json!({"action": "back"}))));
+        assert_eq!(Action::parse("http(post, \"http://hub/{button}\", '{\"a\": 1}', retries=2)").unwrap(), Action::Http(HttpRequest {
+            method: "POST".to_string(),
+            url: "http://hub/{button}".to_string(),
+            body: Some("{\"a\": 1}".to_string()),
+            timeout: None,
+            retries: 2,
+        }));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for input in ["ctrl+alt+del", "alt[a, f]", "hold(enter, 50ms)", "delay(30ms, a, b)*2", "toggle(shift)",
+            "shift(nav, esc)", "mode_toggle(tv, base)", "t9(on)", "pointer_to(50%, 25%)", "release_all",
+            "exec(\"ls -l\", timeout=2s, cwd=/tmp, wait)", "mpris(volume, +5%)", "mpv(seek, 10)", "kodi(Input.Select)"] {
+            let action = Action::parse(input).unwrap();
+            assert_eq!(Action::parse(&action.to_string()).unwrap(), action, "{} -> {}", input, action);
+        }
+    }
+
+    #[test]
+    fn error_columns() {
+        assert_eq!(error("a, foo"), (4, "unknown key 'foo'".to_string()));
+        assert_eq!(error("ctrl+mouse_up"), (6, "mouse movement 'mouse_up' cannot be combined with keys".to_string()));
+        assert_eq!(error("a b"), (3, "unexpected 'b', expected ',' or end of action".to_string()));
+        assert_eq!(error("a*0"), (3, "expected a repeat count after '*'".to_string()));
+        assert_eq!(error("ctrl+[a"), (8, "expected ']', found end of action".to_string()));
+        assert_eq!(error("b, text(\"x"), (8, "unclosed '('".to_string()));
+        assert_eq!(error("hold(enter)"), (1, "hold() takes 2 argument(s), got 1".to_string()));
+        assert_eq!(error("key(0x300)"), (5, "invalid key code '0x300', expected 1 to 767 (0x2ff)".to_string()));
+        assert_eq!(error("sleep(90s)"), (7, "duration '90s' is too long, the maximum is 60s".to_string()));
+        assert_eq!(error("delay(10ms, a, bogus)"), (16, "unknown key 'bogus'".to_string()));
+        assert_eq!(error("http(get, http://hub, retries=40)"), (31, "invalid retry count '40', expected 0 to 10".to_string()));
+        assert_eq!(error("exec('x', foo=1)"), (11, "unknown option 'foo', expected timeout, cwd, user or wait".to_string()));
+        assert_eq!(error("spawn('x', wait)"), (12, "spawn() does not wait, use exec(..., wait)".to_string()));
+        assert_eq!(error("a, exec('x', wait)*2"), (4, "exec(\"x\", wait) cannot be used inside *N".to_string()));
+        assert_eq!(error("kodi(Input.Select, {\"a\": })"), (26, "invalid JSON parameters: expected value".to_string()));
+        assert_eq!(error("mode(x)*2"), (1, "mode(x) cannot be used inside *N".to_string()));
+        assert_eq!(error("a, alt[b, t9(on)]"), (8, "t9(on) cannot be used inside held keys".to_string()));
+        assert_eq!(error("delay(10ms, a, grid(on))"), (13, "grid(on) cannot be used inside delay()".to_string()));
+    }
+}
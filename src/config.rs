@@ -0,0 +1,685 @@
+use crate::action::{Action, BASE_LAYER};
+use crate::BUTTON_NAMES;
+use anyhow::Result;
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub device_name: String,
+    #[serde(default = "default_physical_address")]
+    pub physical_address: u16,
+    #[serde(default = "default_cec_version")]
+    pub cec_version: String,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default)]
+    pub press_mode: PressMode,
+    #[serde(default)]
+    pub mouse: MouseConfig,
+    #[serde(default)]
+    pub scroll: ScrollConfig,
+    #[serde(default)]
+    pub keyboard: KeyboardConfig,
+    /// Pause in milliseconds between the keys of a sequence, for applications that miss fast input
+    #[serde(default)]
+    pub key_delay_ms: u64,
+    /// Maximum time in milliseconds between the buttons of a multi-button sequence
+    #[serde(default = "default_sequence_timeout_ms")]
+    pub sequence_timeout_ms: u64,
+    #[serde(default)]
+    pub sequence_policy: SequencePolicy,
+    #[serde(rename = "mappings")]
+    raw_mappings: HashMap<String, MappingDef>,
+    #[serde(default, rename = "layers")]
+    raw_layers: HashMap<String, HashMap<String, MappingDef>>,
+    /// Button mappings with their actions parsed, filled in by `parse_mappings`
+    #[serde(skip)]
+    pub mappings: HashMap<String, Mapping>,
+    /// Named mapping layers selected with mode actions, falling through to `mappings`
+    #[serde(skip)]
+    pub layers: HashMap<String, HashMap<String, Mapping>>,
+    /// Fail at startup on invalid mapping actions instead of logging and skipping them
+    #[serde(default = "default_strict_mappings")]
+    pub strict_mappings: bool,
+    #[serde(default)]
+    pub t9: T9Config,
+    #[serde(default)]
+    pub mpris: MprisConfig,
+    #[serde(default)]
+    pub mpv: MpvConfig,
+    #[serde(default)]
+    pub kodi: KodiConfig,
+    /// Global cap on accepted button presses, unlimited when not set
+    pub flood_limit: Option<FloodLimit>,
+    /// MQTT bridge, disabled when not set
+    pub mqtt: Option<MqttConfig>,
+}
+
+/// At most `max_presses` button presses are accepted within any `window_ms` milliseconds
+#[derive(Debug, Clone, Deserialize)]
+pub struct FloodLimit {
+    pub max_presses: usize,
+    pub window_ms: u64,
+}
+
+/// How a CEC button press is turned into input events
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PressMode {
+    /// Send a full click on the initial keypress and ignore the release
+    #[default]
+    Click,
+    /// Press keys on the initial keypress and release them when the CEC button is released
+    Hold,
+}
+
+/// What to do when a completed sequence is also the start of a longer one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SequencePolicy {
+    /// Wait for the timeout so the longest matching sequence wins
+    #[default]
+    Longest,
+    /// Send the shorter sequence as soon as it completes
+    Eager,
+}
+
+/// Pointer movement settings for the `mouse_*` direction actions
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MouseConfig {
+    /// Pointer speed in pixels per second when a direction button is first pressed
+    pub base_speed: f64,
+    /// Pointer speed in pixels per second once fully accelerated
+    pub max_speed: f64,
+    /// Time in milliseconds a direction must be held to reach `max_speed`
+    pub accel_time_ms: u64,
+    /// Shape of the acceleration from `base_speed` to `max_speed`
+    pub curve: Curve,
+    /// Interval in milliseconds between motion events while a direction is held
+    pub tick_ms: u64,
+    /// Distance in pixels for a single movement that is not held (sequences, multi-taps)
+    pub step: i32,
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self {
+            base_speed: 100.0,
+            max_speed: 1500.0,
+            accel_time_ms: 1500,
+            curve: Curve::Quadratic,
+            tick_ms: 10,
+            step: 10,
+        }
+    }
+}
+
+/// Scroll wheel settings for the `scroll_*` actions, in wheel notches
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScrollConfig {
+    /// Scroll speed in notches per second when a scroll button is first pressed
+    pub base_speed: f64,
+    /// Scroll speed in notches per second once fully accelerated
+    pub max_speed: f64,
+    /// Time in milliseconds a scroll button must be held to reach `max_speed`
+    pub accel_time_ms: u64,
+    /// Shape of the acceleration from `base_speed` to `max_speed`
+    pub curve: Curve,
+    /// Interval in milliseconds between scroll events while a scroll button is held
+    pub tick_ms: u64,
+    /// Notches for a single scroll that is not held (sequences, multi-taps)
+    pub step: i32,
+    /// Send high-resolution wheel events for smooth scrolling, in addition to whole notches
+    pub hi_res: bool,
+}
+
+impl Default for ScrollConfig {
+    fn default() -> Self {
+        Self {
+            base_speed: 5.0,
+            max_speed: 40.0,
+            accel_time_ms: 2000,
+            curve: Curve::Quadratic,
+            tick_ms: 20,
+            step: 3,
+            hi_res: true,
+        }
+    }
+}
+
+/// Keyboard layout of the system receiving the input, used to type text
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    /// XKB layout the characters of text() actions and T9 entry are typed for
+    pub layout: Layout,
+    /// How to enter characters the layout has no key for
+    pub unicode_input: UnicodeInput,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Us,
+    Gb,
+    De,
+    Fr,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeInput {
+    /// Skip the character with a warning
+    #[default]
+    None,
+    /// Ctrl+Shift+U, the hex code point and Space, understood by GTK and IBus
+    CtrlShiftU,
+}
+
+/// Media player control over D-Bus for the mpris() action
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MprisConfig {
+    /// Player to control (e.g. "vlc" or "mpv"), the most recently playing one when not set
+    pub player: Option<String>,
+    /// D-Bus address of the session bus, for example "unix:path=/run/user/1000/bus".
+    /// Defaults to DBUS_SESSION_BUS_ADDRESS.
+    pub bus: Option<String>,
+}
+
+/// mpv JSON IPC settings for the mpv() action
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MpvConfig {
+    /// Path given to mpv's --input-ipc-server option
+    pub socket: String,
+}
+
+impl Default for MpvConfig {
+    fn default() -> Self {
+        Self { socket: "/tmp/mpvsocket".to_string() }
+    }
+}
+
+/// Kodi JSON-RPC connection for the kodi() action
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KodiConfig {
+    pub transport: KodiTransport,
+    pub host: String,
+    /// Defaults to 8080 for HTTP and 9090 for TCP
+    pub port: Option<u16>,
+    /// Web server login, HTTP only
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Time in milliseconds to wait for Kodi to answer
+    pub timeout_ms: u64,
+}
+
+impl Default for KodiConfig {
+    fn default() -> Self {
+        Self {
+            transport: KodiTransport::Http,
+            host: "localhost".to_string(),
+            port: None,
+            username: None,
+            password: None,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+/// How JSON-RPC requests reach Kodi
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KodiTransport {
+    /// POST to the web server's /jsonrpc ("Allow remote control via HTTP")
+    Http,
+    /// Raw JSON over a kept open TCP connection ("Allow remote control from applications")
+    Tcp,
+}
+
+/// MQTT broker connection, button event topic and command topic
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic every CEC button press and release is published to
+    pub topic: String,
+    /// Topic whose messages are run as actions, none when set to null
+    pub command_topic: Option<String>,
+    /// Run exec() and spawn() actions received on the command topic
+    pub allow_exec: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "cec2uinput".to_string(),
+            username: None,
+            password: None,
+            topic: "cec2uinput/button".to_string(),
+            command_topic: Some("cec2uinput/command".to_string()),
+            allow_exec: false,
+        }
+    }
+}
+
+/// Phone keypad text entry settings, enabled with the t9(on|off|toggle) action
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct T9Config {
+    /// Time in milliseconds after the last tap before the selected letter is typed
+    pub timeout_ms: u64,
+    /// Button toggling upper case letters
+    pub caps: Option<String>,
+    /// Button cancelling the letter being selected, or sending Backspace
+    pub backspace: Option<String>,
+    /// Characters cycled through by repeated taps of each button
+    pub groups: HashMap<String, String>,
+}
+
+impl Default for T9Config {
+    fn default() -> Self {
+        let groups = [
+            ("Number1", ".,?!'-1"),
+            ("Number2", "abc2"),
+            ("Number3", "def3"),
+            ("Number4", "ghi4"),
+            ("Number5", "jkl5"),
+            ("Number6", "mno6"),
+            ("Number7", "pqrs7"),
+            ("Number8", "tuv8"),
+            ("Number9", "wxyz9"),
+            ("Number0", " 0"),
+        ];
+        Self {
+            timeout_ms: 1000,
+            caps: Some("Dot".to_string()),
+            backspace: Some("Clear".to_string()),
+            groups: groups.iter().map(|(b, g)| (b.to_string(), g.to_string())).collect(),
+        }
+    }
+}
+
+/// Acceleration curve shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    Linear,
+    Quadratic,
+    Cubic,
+    /// Slow start and gentle approach to the maximum speed (smoothstep)
+    Smooth,
+}
+
+/// Actions bound to a single CEC button.
+///
+/// Written either as a plain action string (`Exit: "esc"`) or as a map with
+/// separate short and long press actions (`Exit: { tap: "esc", hold: "alt+f4", hold_ms: 800 }`)
+/// and optional multi-tap actions (`Select: { tap: "enter", double: "mouse_click_right" }`).
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    /// Action for a short press
+    pub tap: Option<Action>,
+    /// Action for a press held at least `hold_ms`
+    pub hold: Option<Action>,
+    /// Minimum press duration in milliseconds that selects the `hold` action
+    pub hold_ms: u64,
+    /// Action for two taps within `tap_window_ms`
+    pub double: Option<Action>,
+    /// Action for three taps within `tap_window_ms`
+    pub triple: Option<Action>,
+    /// Maximum time in milliseconds between taps counted as one multi-tap
+    pub tap_window_ms: u64,
+    /// Auto-repeat of the `tap` action while the button is held
+    pub repeat: Option<Repeat>,
+    /// Presses arriving sooner than this many milliseconds after the previous one are dropped
+    pub min_interval_ms: u64,
+}
+
+/// Software auto-repeat settings for a held button
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Repeat {
+    /// Time in milliseconds between the initial action and the first repeat
+    #[serde(default = "default_repeat_delay_ms")]
+    pub delay_ms: u64,
+    /// Time in milliseconds between repeats
+    #[serde(default = "default_repeat_interval_ms")]
+    pub interval_ms: u64,
+    /// Factor applied to the interval after every repeat, below 1.0 speeds repeats up
+    #[serde(default = "default_repeat_acceleration")]
+    pub acceleration: f64,
+    /// Lower bound in milliseconds for the accelerated interval
+    #[serde(default = "default_repeat_min_interval_ms")]
+    pub min_interval_ms: u64,
+}
+
+impl Mapping {
+    /// Highest tap count with its own action, 1 if the mapping has no multi-tap actions
+    pub fn max_taps(&self) -> u32 {
+        if self.triple.is_some() {
+            3
+        } else if self.double.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// All actions of the mapping
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        [&self.tap, &self.hold, &self.double, &self.triple].into_iter().flatten()
+    }
+
+    /// Actions to send for `count` taps, repeating the single tap when no multi-tap action is set
+    pub fn tap_actions(&self, count: u32) -> Vec<Action> {
+        let action = match count {
+            1 => &self.tap,
+            2 => &self.double,
+            _ => &self.triple,
+        };
+        match action {
+            Some(action) if count > 1 => vec![action.clone()],
+            _ => self.tap.iter().cloned().cycle().take(count as usize).collect(),
+        }
+    }
+}
+
+impl Config {
+    /// Parse the action strings of `mappings` and `layers`.
+    ///
+    /// Invalid actions, unknown button names and modes that do not exist fail with an error
+    /// naming the mapping and column, or with `strict_mappings: false` are logged and left unmapped.
+    pub fn parse_mappings(&mut self) -> Result<()> {
+        let mut errors = Vec::new();
+        let layer_names: Vec<&str> = self.raw_layers.keys().map(String::as_str).collect();
+
+        self.mappings = parse_layer("mappings", &self.raw_mappings, true, &layer_names, &mut errors);
+        self.layers = self.raw_layers.iter()
+            .map(|(name, layer)| {
+                let parsed = parse_layer(&format!("layers.{}", name), layer, false, &layer_names, &mut errors);
+                (name.clone(), parsed)
+            })
+            .collect();
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort();
+        if self.strict_mappings {
+            anyhow::bail!("Invalid mappings in configuration:\n{}", errors.join("\n"));
+        }
+        for error in &errors {
+            warn!("Ignoring invalid mapping {}", error);
+        }
+        Ok(())
+    }
+}
+
+// `sequences` allows keys naming several buttons, which are only matched in `mappings`
+fn parse_layer(
+    path: &str,
+    defs: &HashMap<String, MappingDef>,
+    sequences: bool,
+    layer_names: &[&str],
+    errors: &mut Vec<String>,
+) -> HashMap<String, Mapping> {
+    let mut mappings = HashMap::new();
+    for (button, def) in defs {
+        // Sequence keys name several buttons separated by whitespace
+        let unknown: Vec<&str> = button.split_whitespace().filter(|b| !BUTTON_NAMES.contains(b)).collect();
+        if !unknown.is_empty() || button.trim().is_empty() {
+            errors.push(format!("{}.{}: unknown CEC button '{}'", path, button, unknown.join("', '")));
+            continue;
+        }
+        if button.split_whitespace().nth(1).is_some() {
+            if !sequences {
+                errors.push(format!("{}.{}: sequences only work in mappings, not in layers", path, button));
+                continue;
+            }
+            // A sequence sends one action when it completes, there is no press to hold or tap again
+            if let MappingDef::Detailed(def) = def {
+                let other = def.hold.is_some() || def.double.is_some() || def.triple.is_some()
+                    || def.repeat.is_some() || def.min_interval_ms != 0;
+                if other || def.tap.is_none() {
+                    errors.push(format!("{}.{}: sequences only take a tap action", path, button));
+                    continue;
+                }
+            }
+        }
+        mappings.insert(button.clone(), def.parse(&format!("{}.{}", path, button), layer_names, errors));
+    }
+    mappings
+}
+
+// Parse a single action string, `path` naming the mapping field in errors
+fn parse_action(path: &str, text: &str, layer_names: &[&str]) -> Result<Action, String> {
+    let action = Action::parse(text).map_err(|e| {
+        format!("{}: {}\n    {}\n    {}^", path, e, text, " ".repeat(e.column.saturating_sub(1)))
+    })?;
+
+    let mut unknown = None;
+    action.walk(&mut |a| {
+        let modes = match a {
+            Action::Mode(layer) | Action::ModeMomentary(layer) => vec![layer.as_deref()],
+            Action::ModeToggle(first, second) => vec![first.as_deref(), second.as_deref()],
+            Action::Shift(layer, _) => vec![Some(layer.as_str())],
+            _ => Vec::new(),
+        };
+        for mode in modes.into_iter().flatten() {
+            if mode != BASE_LAYER && !layer_names.contains(&mode) && unknown.is_none() {
+                unknown = Some(mode.to_string());
+            }
+        }
+    });
+    match unknown {
+        Some(mode) => Err(format!("{}: unknown mode '{}' in '{}'", path, mode, text)),
+        None => Ok(action),
+    }
+}
+
+#[derive(Debug)]
+enum MappingDef {
+    Action(String),
+    Detailed(DetailedMapping),
+}
+
+// The map form of a mapping; unknown fields are rejected so a typo such as `hodl` is not ignored
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedMapping {
+    tap: Option<String>,
+    hold: Option<String>,
+    #[serde(default = "default_hold_ms")]
+    hold_ms: u64,
+    double: Option<String>,
+    triple: Option<String>,
+    #[serde(default = "default_tap_window_ms")]
+    tap_window_ms: u64,
+    repeat: Option<Repeat>,
+    #[serde(default)]
+    min_interval_ms: u64,
+}
+
+// Written by hand instead of as an untagged enum, whose only error is "data did not match any
+// variant", so that errors in the map form name the offending field
+impl<'de> Deserialize<'de> for MappingDef {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_yaml_ng::Value::deserialize(deserializer)? {
+            serde_yaml_ng::Value::String(action) => Ok(MappingDef::Action(action)),
+            value => DetailedMapping::deserialize(value)
+                .map(MappingDef::Detailed)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl MappingDef {
+    // Parse the action strings, collecting errors and leaving invalid actions unset
+    fn parse(&self, path: &str, layer_names: &[&str], errors: &mut Vec<String>) -> Mapping {
+        // A zero interval would send repeats as fast as the loop can spin
+        let repeat = match self {
+            MappingDef::Detailed(def) => def.repeat.clone().filter(|repeat| {
+                let valid = repeat.interval_ms >= MIN_REPEAT_INTERVAL_MS && repeat.min_interval_ms >= MIN_REPEAT_INTERVAL_MS;
+                if !valid {
+                    errors.push(format!("{}.repeat: interval_ms and min_interval_ms must be at least {} ms",
+                                        path, MIN_REPEAT_INTERVAL_MS));
+                }
+                valid
+            }),
+            MappingDef::Action(_) => None,
+        };
+        let mut parse = |field: &str, text: Option<&String>| {
+            let text = text?;
+            parse_action(&format!("{}.{}", path, field), text, layer_names)
+                .map_err(|e| errors.push(e))
+                .ok()
+        };
+
+        match self {
+            MappingDef::Action(action) => Mapping {
+                tap: parse("tap", Some(action)),
+                hold: None,
+                hold_ms: default_hold_ms(),
+                double: None,
+                triple: None,
+                tap_window_ms: default_tap_window_ms(),
+                repeat: None,
+                min_interval_ms: 0,
+            },
+            MappingDef::Detailed(def) => Mapping {
+                tap: parse("tap", def.tap.as_ref()),
+                hold: parse("hold", def.hold.as_ref()),
+                hold_ms: def.hold_ms,
+                double: parse("double", def.double.as_ref()),
+                triple: parse("triple", def.triple.as_ref()),
+                tap_window_ms: def.tap_window_ms,
+                repeat,
+                min_interval_ms: def.min_interval_ms,
+            },
+        }
+    }
+}
+
+// Shortest auto-repeat interval accepted in `repeat`
+const MIN_REPEAT_INTERVAL_MS: u64 = 10;
+
+fn default_physical_address() -> u16 {
+    0x1000  // Default to HDMI port 1
+}
+
+fn default_cec_version() -> String {
+    "1.4".to_string()
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_strict_mappings() -> bool {
+    true
+}
+
+fn default_sequence_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_hold_ms() -> u64 {
+    500
+}
+
+fn default_tap_window_ms() -> u64 {
+    300
+}
+
+fn default_repeat_delay_ms() -> u64 {
+    400
+}
+
+fn default_repeat_interval_ms() -> u64 {
+    100
+}
+
+fn default_repeat_acceleration() -> f64 {
+    1.0
+}
+
+fn default_repeat_min_interval_ms() -> u64 {
+    20
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(yaml: &str) -> Result<Config> {
+        let mut config: Config = serde_yaml_ng::from_str(&format!("device_name: test\n{}", yaml))?;
+        config.parse_mappings()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn detailed_mapping() {
+        let config = load("mappings:\n  Exit: { tap: esc, hold: 'alt+f4', hold_ms: 800 }\n").unwrap();
+        let mapping = &config.mappings["Exit"];
+        assert_eq!(mapping.tap.as_ref().unwrap().to_string(), "esc");
+        assert_eq!(mapping.hold.as_ref().unwrap().to_string(), "alt+f4");
+        assert_eq!(mapping.hold_ms, 800);
+        assert_eq!(mapping.tap_window_ms, default_tap_window_ms());
+    }
+
+    #[test]
+    fn unknown_button() {
+        let error = load("mappings:\n  Upp: up\n  Up Dwn: esc\n  Up Down: esc\n").unwrap_err().to_string();
+        assert!(error.contains("mappings.Upp: unknown CEC button 'Upp'"), "{}", error);
+        assert!(error.contains("mappings.Up Dwn: unknown CEC button 'Dwn'"), "{}", error);
+        assert!(!error.contains("Up Down"), "{}", error);
+
+        let config = load("strict_mappings: false\nmappings: {}\nlayers:\n  tv:\n    Upp: up\n    Down: down\n").unwrap();
+        assert!(!config.layers["tv"].contains_key("Upp"));
+        assert!(config.layers["tv"].contains_key("Down"));
+    }
+
+    #[test]
+    fn zero_repeat_interval() {
+        let error = load("mappings:\n  Up: { tap: up, repeat: { interval_ms: 0, min_interval_ms: 0 } }\n")
+            .unwrap_err().to_string();
+        assert!(error.contains("mappings.Up.repeat: interval_ms and min_interval_ms must be at least 10 ms"), "{}", error);
+        let config = load("mappings:\n  Up: { tap: up, repeat: { interval_ms: 50 } }\n").unwrap();
+        assert_eq!(config.mappings["Up"].repeat.as_ref().unwrap().interval_ms, 50);
+    }
+
+    #[test]
+    fn sequence_forms() {
+        let config = load("mappings:\n  Number1 Number2: a\n  Up Down: { tap: b }\n").unwrap();
+        assert_eq!(config.mappings["Up Down"].tap.as_ref().unwrap().to_string(), "b");
+
+        let error = load("mappings:\n  Up Down: { tap: a, hold: b }\n  Left Right: { double: c }\n")
+            .unwrap_err().to_string();
+        assert!(error.contains("mappings.Up Down: sequences only take a tap action"), "{}", error);
+        assert!(error.contains("mappings.Left Right: sequences only take a tap action"), "{}", error);
+        let error = load("mappings: {}\nlayers:\n  tv:\n    Up Down: a\n").unwrap_err().to_string();
+        assert!(error.contains("layers.tv.Up Down: sequences only work in mappings, not in layers"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_mapping_field() {
+        let error = load("mappings:\n  Exit: { tap: esc, hodl: 'alt+f4' }\n").unwrap_err().to_string();
+        assert!(error.contains("unknown field `hodl`"), "{}", error);
+        let error = load("mappings:\n  Up: { tap: up, repeat: { interval: 50 } }\n").unwrap_err().to_string();
+        assert!(error.contains("unknown field `interval`"), "{}", error);
+    }
+}
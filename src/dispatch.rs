@@ -0,0 +1,688 @@
+use anyhow::Result;
+use crate::action::{Action, Switch, BASE_LAYER};
+use crate::config::{Config, Mapping, PressMode};
+use crate::executor::{Executor, Trigger};
+use crate::grid::Grid;
+use crate::pointer::Pointer;
+use crate::ratelimit::RateLimiter;
+use crate::sequence::{SequenceMatcher, SequenceOutput};
+use crate::t9::{T9, T9Output};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+
+/// Turns CEC button presses and releases into actions on the virtual device.
+///
+/// Drops duplicate or flooding presses, handles T9 text entry, grid jumps and multi-button sequences, then keeps the per-button state needed for hold press mode,
+/// long-press, multi-tap and auto-repeat mappings, and drives continuous pointer motion and
+/// scrolling for held mouse direction and scroll buttons.
+/// Buttons resolve through the layers of held shift buttons and the active mapping layer (mode)
+/// before falling back to `mappings`.
+/// Timers are driven by the main loop through `next_deadline` and `tick`.
+pub struct Dispatcher {
+    config: Config,
+    device: Executor,
+    // Keys held down per CEC button while in hold press mode, released on the matching release frame
+    held_keys: HashMap<&'static str, Vec<u16>>,
+    // Buttons with a long-press mapping waiting for their release or hold timer
+    pending_holds: HashMap<&'static str, PendingHold>,
+    // Buttons with multi-tap mappings waiting for further taps
+    pending_taps: HashMap<&'static str, TapState>,
+    // Held buttons whose tap action is being auto-repeated
+    repeating: HashMap<&'static str, RepeatState>,
+    pointer: Pointer,
+    scroller: Pointer,
+    sequences: SequenceMatcher,
+    // Active mapping layer, None for the base `mappings`
+    mode: Option<String>,
+    // Layer used for the next button press only
+    one_shot: Option<String>,
+    // Held shift buttons, most recently pressed last
+    shifts: Vec<Shift>,
+    t9: T9,
+    grid: Grid,
+    limiter: RateLimiter,
+    // CEC button and hold duration the current actions run for, passed to exec() commands and http() requests
+    event: (&'static str, Duration),
+}
+
+struct Shift {
+    button: &'static str,
+    layer: String,
+    // Action sent on release when no other button was pressed while shifted
+    fallback: Option<Action>,
+    used: bool,
+}
+
+// Pending states keep the mapping resolved at press time, so a mode change while a
+// button is down does not change what its release or timers do
+
+struct PendingHold {
+    // When the hold action fires if no release arrives
+    deadline: Instant,
+    mapping: Mapping,
+}
+
+struct TapState {
+    count: u32,
+    // When the tap window closes and the action for `count` taps is sent
+    deadline: Instant,
+    mapping: Mapping,
+}
+
+struct RepeatState {
+    action: Action,
+    next: Instant,
+    // Current interval in milliseconds, shrinking with acceleration
+    interval_ms: f64,
+    mapping: Mapping,
+}
+
+impl Dispatcher {
+    pub fn new(config: Config, device: Executor) -> Result<Self> {
+        let pointer = Pointer::new(config.mouse.clone());
+        let scroller = Pointer::scroll(&config.scroll);
+        let sequences = SequenceMatcher::new(&config);
+        let t9 = T9::new(config.t9.clone());
+        let limiter = RateLimiter::new(config.flood_limit.clone());
+        Ok(Self {
+            config,
+            device,
+            held_keys: HashMap::new(),
+            pending_holds: HashMap::new(),
+            pending_taps: HashMap::new(),
+            repeating: HashMap::new(),
+            pointer,
+            scroller,
+            sequences,
+            mode: None,
+            one_shot: None,
+            shifts: Vec::new(),
+            t9,
+            grid: Grid::new(),
+            limiter,
+            event: ("", Duration::ZERO),
+        })
+    }
+
+    /// Handle the initial keypress of a CEC button
+    pub fn handle_press(&mut self, button: &'static str, now: Instant) -> Result<()> {
+        let min_interval = self.lookup(button).map(|m| m.min_interval_ms).unwrap_or(0);
+        if !self.limiter.allow(button, Duration::from_millis(min_interval), now) {
+            return Ok(());
+        }
+        self.event = (button, Duration::ZERO);
+
+        if self.t9.is_active() {
+            if let Some(outputs) = self.t9.press(button, now) {
+                return self.type_t9(outputs);
+            }
+            // Any other button completes the letter being selected
+            if let Some(c) = self.t9.commit() {
+                self.type_t9(vec![T9Output::Char(c)])?;
+            }
+        }
+        if self.grid.is_active() {
+            if let Some((x, y)) = self.grid.press(button) {
+                return self.send(&Action::PointerTo(x, y));
+            }
+        }
+
+        let outputs = self.sequences.press(button, now);
+        self.apply_sequence_outputs(outputs, now)
+    }
+
+    /// Handle the release of a CEC button, `duration` being how long libcec saw it held
+    pub fn handle_release(&mut self, button: &'static str, duration: Duration) -> Result<()> {
+        self.event = (button, duration);
+        let outputs = self.sequences.release(button, duration);
+        self.apply_sequence_outputs(outputs, Instant::now())
+    }
+
+    /// Run an action received from outside the remote, such as an MQTT command
+    pub fn handle_command(&mut self, action: &Action) -> Result<()> {
+        self.event = ("MQTT", Duration::ZERO);
+        self.run_action(action)
+    }
+
+    /// Name of the active mapping layer
+    pub fn mode(&self) -> &str {
+        self.mode.as_deref().unwrap_or(BASE_LAYER)
+    }
+
+    fn apply_sequence_outputs(&mut self, outputs: Vec<SequenceOutput>, now: Instant) -> Result<()> {
+        for output in outputs {
+            match output {
+                SequenceOutput::Action(action) => {
+                    debug!("Sending sequence action '{}'", action);
+                    self.run_action(&action)?;
+                }
+                SequenceOutput::Press(button) => self.press_button(button, now)?,
+                SequenceOutput::Release(button, duration) => self.release_button(button, duration)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Handle a press that is not part of a multi-button sequence
+    fn press_button(&mut self, button: &'static str, now: Instant) -> Result<()> {
+        // Taps pending on other buttons are complete once a different button is pressed
+        let others: Vec<&'static str> = self.pending_taps.keys().copied().filter(|b| *b != button).collect();
+        for other in others {
+            self.flush_taps(other)?;
+        }
+        // Pressing any button ends auto-repeat in case a release frame was lost
+        self.repeating.clear();
+
+        // A second press of a shift button without a release means the release frame was lost
+        self.shifts.retain(|shift| shift.button != button);
+        for shift in self.shifts.iter_mut() {
+            shift.used = true;
+        }
+
+        // Pointer motion and scrolling end the same way, except for another direction held
+        // together with them to move diagonally
+        let tap = self.lookup(button)
+            .filter(|mapping| mapping.hold.is_none() && mapping.max_taps() == 1 && mapping.repeat.is_none())
+            .and_then(|mapping| mapping.tap.as_ref());
+        let (moves, scrolls) = (matches!(tap, Some(Action::MouseMove(..))), matches!(tap, Some(Action::Scroll(..))));
+        if !moves && self.pointer.stop_all() {
+            debug!("Stopping pointer motion still running when CEC event '{}' was pressed", button);
+        }
+        if !scrolls && self.scroller.stop_all() {
+            debug!("Stopping scrolling still running when CEC event '{}' was pressed", button);
+        }
+
+        let Some(mapping) = self.resolve(button) else {
+            warn!("No mapping found for CEC event: {}", button);
+            return Ok(());
+        };
+
+        // A second press without a release in between means the release frame was lost
+        if let Some(pending) = self.pending_holds.remove(button) {
+            if pending.mapping.max_taps() == 1 {
+                debug!("CEC event '{}' pressed again before release, treating previous press as tap", button);
+                if let Some(action) = &pending.mapping.tap {
+                    self.run_action(action)?;
+                }
+            }
+        }
+        if let Some(keys) = self.held_keys.remove(button) {
+            debug!("Releasing keys still held by CEC event '{}'", button);
+            self.device.release_keys(&keys)?;
+        }
+
+        // Multi-tap buttons wait for the tap window to close, unless the highest tap count is reached
+        if mapping.max_taps() > 1 {
+            let deadline = now + Duration::from_millis(mapping.tap_window_ms);
+            let state = self.pending_taps.entry(button)
+                .or_insert_with(|| TapState { count: 0, deadline, mapping: mapping.clone() });
+            state.count += 1;
+            state.deadline = deadline;
+            let count = state.count;
+            if count >= mapping.max_taps() {
+                return self.flush_taps(button);
+            }
+            if count == 1 && mapping.hold.is_some() {
+                let deadline = now + Duration::from_millis(mapping.hold_ms);
+                self.pending_holds.insert(button, PendingHold { deadline, mapping });
+            }
+            return Ok(());
+        }
+
+        // With a long-press action the choice is only known on release or when the hold timer fires
+        if mapping.hold.is_some() {
+            let deadline = now + Duration::from_millis(mapping.hold_ms);
+            self.pending_holds.insert(button, PendingHold { deadline, mapping });
+            return Ok(());
+        }
+
+        let Some(action) = mapping.tap.clone() else {
+            return Ok(());
+        };
+
+        // Shift buttons switch layers for as long as they are held
+        if let Action::Shift(layer, fallback) = &action {
+            if let Some(layer) = self.layer(Some(layer)) {
+                debug!("Shifting to mode '{}' while CEC event '{}' is held", layer, button);
+                let fallback = fallback.as_deref().cloned();
+                self.shifts.push(Shift { button, layer, fallback, used: false });
+            }
+            return Ok(());
+        }
+
+        if self.run_control_action(&action)? {
+            return Ok(());
+        }
+
+        // Mouse directions keep moving the pointer until the release
+        if let Action::MouseMove(x, y) = action {
+            debug!("Moving pointer for CEC event '{}' ({})", button, action);
+            self.pointer.start(button, (x as f64, y as f64), now);
+            return Ok(());
+        }
+        if let Action::Scroll(x, y) = action {
+            debug!("Scrolling for CEC event '{}' ({})", button, action);
+            self.scroller.start(button, (x as f64, y as f64), now);
+            return Ok(());
+        }
+
+        // Auto-repeat sends the action right away and then keeps clicking it until the release
+        if let Some(repeat) = &mapping.repeat {
+            debug!("Mapping CEC event '{}' to input event '{}' with auto-repeat", button, action);
+            self.send(&action)?;
+            let next = now + Duration::from_millis(repeat.delay_ms);
+            let interval_ms = repeat.interval_ms as f64;
+            self.repeating.insert(button, RepeatState { action, next, interval_ms, mapping });
+            return Ok(());
+        }
+
+        self.press_action(button, &action)
+    }
+
+    // Handle a release that is not part of a multi-button sequence
+    fn release_button(&mut self, button: &'static str, duration: Duration) -> Result<()> {
+        if let Some(idx) = self.shifts.iter().position(|shift| shift.button == button) {
+            let shift = self.shifts.remove(idx);
+            debug!("Leaving shifted mode '{}' after {} ms", shift.layer, duration.as_millis());
+            if let (false, Some(action)) = (shift.used, shift.fallback) {
+                self.run_action(&action)?;
+            }
+            return Ok(());
+        }
+        if self.pointer.stop(button) {
+            debug!("Stopping pointer motion for CEC event '{}' after {} ms", button, duration.as_millis());
+            return Ok(());
+        }
+        if self.scroller.stop(button) {
+            debug!("Stopping scrolling for CEC event '{}' after {} ms", button, duration.as_millis());
+            return Ok(());
+        }
+        if self.repeating.remove(button).is_some() {
+            debug!("Stopping auto-repeat for CEC event '{}' after {} ms", button, duration.as_millis());
+            return Ok(());
+        }
+
+        if let Some(PendingHold { mapping, .. }) = self.pending_holds.remove(button) {
+            let long = duration.as_millis() >= mapping.hold_ms as u128;
+            if long {
+                self.pending_taps.remove(button);
+            }
+            let action = if long {
+                &mapping.hold
+            } else if mapping.max_taps() > 1 {
+                // Short press of a multi-tap button, the tap window decides the action
+                return Ok(());
+            } else {
+                &mapping.tap
+            };
+            if let Some(action) = action {
+                debug!("CEC event '{}' released after {} ms, sending {} action '{}'",
+                       button, duration.as_millis(), if long { "hold" } else { "tap" }, action);
+                self.run_action(action)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(keys) = self.held_keys.remove(button) {
+            debug!("Releasing input event for CEC event '{}' after {} ms", button, duration.as_millis());
+            self.device.release_keys(&keys)?;
+        }
+        Ok(())
+    }
+
+    /// Earliest time at which `tick` has work to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let t9 = self.t9.next_deadline();
+        let holds = self.pending_holds.values().map(|pending| pending.deadline);
+        let taps = self.pending_taps.iter()
+            .filter(|(button, _)| !self.pending_holds.contains_key(*button))
+            .map(|(_, state)| state.deadline);
+        let repeats = self.repeating.values().map(|state| state.next);
+        holds.chain(taps).chain(repeats)
+            .chain(self.pointer.next_deadline())
+            .chain(self.scroller.next_deadline())
+            .chain(self.sequences.next_deadline())
+            .chain(t9)
+            .min()
+    }
+
+    /// Fire timers that expired by `now`
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        if let Some(c) = self.t9.tick(now) {
+            self.type_t9(vec![T9Output::Char(c)])?;
+        }
+
+        let outputs = self.sequences.tick(now);
+        self.apply_sequence_outputs(outputs, now)?;
+
+        let expired: Vec<&'static str> = self.pending_holds.iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(button, _)| *button)
+            .collect();
+
+        for button in expired {
+            self.pending_taps.remove(button);
+            // No release arrived within hold_ms, so this is a long press
+            let Some(PendingHold { mapping, .. }) = self.pending_holds.remove(button) else {
+                continue;
+            };
+            if let Some(action) = mapping.hold {
+                debug!("CEC event '{}' held, sending hold action '{}'", button, action);
+                self.event = (button, Duration::from_millis(mapping.hold_ms));
+                self.run_action(&action)?;
+            }
+        }
+
+        // The tap window only closes once a button still being held is known to be a short press
+        let closed: Vec<&'static str> = self.pending_taps.iter()
+            .filter(|(button, state)| state.deadline <= now && !self.pending_holds.contains_key(*button))
+            .map(|(button, _)| *button)
+            .collect();
+
+        for button in closed {
+            self.flush_taps(button)?;
+        }
+
+        let mode = self.mode().to_string();
+        for (button, state) in self.repeating.iter_mut() {
+            if state.next > now {
+                continue;
+            }
+            debug!("Auto-repeating input event '{}' for CEC event '{}'", state.action, button);
+            let trigger = Trigger { button, duration: Duration::ZERO, mode: mode.clone() };
+            self.device.send_key(&state.action, trigger)?;
+
+            if let Some(repeat) = &state.mapping.repeat {
+                state.interval_ms = (state.interval_ms * repeat.acceleration)
+                    .clamp(repeat.min_interval_ms as f64, repeat.interval_ms.max(repeat.min_interval_ms) as f64);
+            }
+            let interval = Duration::from_secs_f64(state.interval_ms / 1000.0);
+            state.next += interval;
+            // Skip missed repeats instead of bursting them out if the loop was late
+            if state.next <= now {
+                state.next = now + interval;
+            }
+        }
+
+        if let Some((x, y)) = self.pointer.tick(now) {
+            self.device.move_mouse(x, y)?;
+        }
+        if let Some((x, y)) = self.scroller.tick(now) {
+            self.device.scroll(x, y)?;
+        }
+        Ok(())
+    }
+
+    // Send the action for the taps counted so far on a multi-tap button
+    fn flush_taps(&mut self, button: &'static str) -> Result<()> {
+        let Some(state) = self.pending_taps.remove(button) else {
+            return Ok(());
+        };
+        self.pending_holds.remove(button);
+        let event = std::mem::replace(&mut self.event, (button, Duration::ZERO));
+        for action in state.mapping.tap_actions(state.count) {
+            debug!("CEC event '{}' tapped {} time(s), sending '{}'", button, state.count, action);
+            self.run_action(&action)?;
+        }
+        self.event = event;
+        Ok(())
+    }
+
+    // Resolve the mapping for a button press, using up the one-shot layer
+    fn resolve(&mut self, button: &str) -> Option<Mapping> {
+        let mapping = self.lookup(button).cloned();
+        self.one_shot = None;
+        mapping
+    }
+
+    // Look up the mapping for a button in the layers of held shift buttons, the one-shot layer,
+    // the active layer, then `mappings`
+    fn lookup(&self, button: &str) -> Option<&Mapping> {
+        let shifted = self.shifts.iter().rev().map(|shift| &shift.layer);
+        for layer in shifted.chain(self.one_shot.iter()).chain(self.mode.iter()) {
+            if let Some(mapping) = self.config.layers.get(layer).and_then(|l| l.get(button)) {
+                return Some(mapping);
+            }
+        }
+        self.config.mappings.get(button)
+    }
+
+    // Queue an action on the input thread for the current CEC event
+    fn send(&self, action: &Action) -> Result<()> {
+        self.device.send_key(action, self.trigger())
+    }
+
+    fn trigger(&self) -> Trigger {
+        let (button, duration) = self.event;
+        Trigger { button, duration, mode: self.mode().to_string() }
+    }
+
+    // Send an action that is not held, handling mode switches here instead of on the device
+    fn run_action(&mut self, action: &Action) -> Result<()> {
+        // Mode switches in a sequence take effect right away; the other items go to the device
+        // together so delays between them still apply
+        let items = match action {
+            Action::Sequence(items) => items.as_slice(),
+            _ => std::slice::from_ref(action),
+        };
+        let mut rest = Vec::new();
+        for item in items {
+            if !self.run_control_action(item)? {
+                rest.push(item.clone());
+            }
+        }
+        match rest.len() {
+            0 => Ok(()),
+            1 => self.send(&rest[0]),
+            _ => self.send(&Action::Sequence(rest)),
+        }
+    }
+
+    fn type_t9(&mut self, outputs: Vec<T9Output>) -> Result<()> {
+        for output in outputs {
+            match output {
+                T9Output::Char(c) => {
+                    debug!("T9 typing '{}'", c);
+                    self.device.type_char(c)?;
+                }
+                T9Output::Backspace => self.send(&Action::parse("backspace")?)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Handle mode(layer), mode_toggle(a, b), mode_momentary(layer), t9(on|off|toggle) and
+    // grid(on|off|toggle); returns false for other actions
+    fn run_control_action(&mut self, action: &Action) -> Result<bool> {
+        match action {
+            Action::Mode(layer) => {
+                self.mode = self.layer(layer.as_deref());
+                info!("Switched to mode '{}'", self.mode.as_deref().unwrap_or(BASE_LAYER));
+            }
+            Action::ModeToggle(first, second) => {
+                let next = if self.mode == *first { second } else { first };
+                self.mode = self.layer(next.as_deref());
+                info!("Switched to mode '{}'", self.mode.as_deref().unwrap_or(BASE_LAYER));
+            }
+            Action::ModeMomentary(layer) => {
+                self.one_shot = self.layer(layer.as_deref());
+                debug!("Using mode '{}' for the next button", layer.as_deref().unwrap_or(BASE_LAYER));
+            }
+            Action::Shift(..) => {
+                warn!("Action '{}' only works as the tap action of a button", action);
+            }
+            Action::T9(switch) => {
+                let active = match switch {
+                    Switch::On => true,
+                    Switch::Off => false,
+                    Switch::Toggle => !self.t9.is_active(),
+                };
+                info!("T9 text entry {}", if active { "on" } else { "off" });
+                if let Some(c) = self.t9.set_active(active) {
+                    self.type_t9(vec![T9Output::Char(c)])?;
+                }
+            }
+            Action::Grid(switch) => {
+                let active = match switch {
+                    Switch::On => true,
+                    Switch::Off => false,
+                    Switch::Toggle => !self.grid.is_active(),
+                };
+                info!("Grid jump mode {}", if active { "on" } else { "off" });
+                if let Some((x, y)) = self.grid.set_active(active) {
+                    self.send(&Action::PointerTo(x, y))?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Validate a layer name, mapping the base layer and unknown layers to None
+    fn layer(&self, name: Option<&str>) -> Option<String> {
+        let name = name.filter(|name| *name != BASE_LAYER)?;
+        if !self.config.layers.contains_key(name) {
+            warn!("Unknown mode '{}', using base mappings", name);
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    // Send an action for a button press according to the configured press mode
+    fn press_action(&mut self, button: &'static str, action: &Action) -> Result<()> {
+        match self.config.press_mode {
+            PressMode::Click => {
+                debug!("Mapping CEC event '{}' to input event '{}'", button, action);
+                self.run_action(action)?;
+            }
+            // Actions that cannot be held are sent as a click, with their mode switches handled here
+            PressMode::Hold if action.held_keys().is_none() => self.run_action(action)?,
+            PressMode::Hold => {
+                debug!("Pressing input event '{}' for CEC event '{}'", action, button);
+                let keys = self.device.press_action(action, self.trigger())?;
+                if !keys.is_empty() {
+                    self.held_keys.insert(button, keys);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Command;
+    use std::sync::mpsc::Receiver;
+
+    fn dispatcher(mappings: &str) -> (Dispatcher, Receiver<Command>) {
+        let mut config: Config = serde_yaml_ng::from_str(&format!("device_name: test\nmappings:\n{}", mappings)).unwrap();
+        config.parse_mappings().unwrap();
+        let (executor, rx) = Executor::channel();
+        (Dispatcher::new(config, executor).unwrap(), rx)
+    }
+
+    // Actions queued since the last call, with the hold duration they were sent for
+    fn sent(rx: &Receiver<Command>) -> Vec<(String, u128)> {
+        rx.try_iter().map(|command| match command {
+            Command::Send(action, trigger) => (action.to_string(), trigger.duration.as_millis()),
+            other => panic!("unexpected command {:?}", other),
+        }).collect()
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn long_press() {
+        let (mut d, rx) = dispatcher("  Exit: { tap: esc, hold: 'alt+f4', hold_ms: 500 }\n");
+        let now = Instant::now();
+
+        d.handle_press("Exit", now).unwrap();
+        assert_eq!(d.next_deadline(), Some(now + ms(500)));
+        d.tick(now + ms(499)).unwrap();
+        assert!(sent(&rx).is_empty());
+        d.tick(now + ms(500)).unwrap();
+        assert_eq!(sent(&rx), [("alt+f4".to_string(), 500)]);
+        d.handle_release("Exit", ms(700)).unwrap();
+        assert!(sent(&rx).is_empty());
+
+        // A release before the timer fires decides by the duration libcec reports
+        d.handle_press("Exit", now + ms(1000)).unwrap();
+        d.handle_release("Exit", ms(120)).unwrap();
+        assert_eq!(sent(&rx), [("esc".to_string(), 120)]);
+        d.handle_press("Exit", now + ms(2000)).unwrap();
+        d.handle_release("Exit", ms(500)).unwrap();
+        assert_eq!(sent(&rx), [("alt+f4".to_string(), 500)]);
+        assert_eq!(d.next_deadline(), None);
+    }
+
+    #[test]
+    fn multi_tap() {
+        let (mut d, rx) = dispatcher("  Select: { tap: enter, double: space, tap_window_ms: 300 }\n");
+        let now = Instant::now();
+
+        // The highest tap count sends right away
+        d.handle_press("Select", now).unwrap();
+        d.handle_release("Select", ms(50)).unwrap();
+        d.handle_press("Select", now + ms(200)).unwrap();
+        assert_eq!(sent(&rx), [("space".to_string(), 0)]);
+        d.handle_release("Select", ms(50)).unwrap();
+
+        // A single tap waits for the window to close, which every tap extends
+        d.handle_press("Select", now + ms(1000)).unwrap();
+        d.handle_release("Select", ms(50)).unwrap();
+        d.tick(now + ms(1299)).unwrap();
+        assert!(sent(&rx).is_empty());
+        d.tick(now + ms(1300)).unwrap();
+        assert_eq!(sent(&rx), [("enter".to_string(), 0)]);
+
+        // Another button closes the window early
+        d.handle_press("Select", now + ms(2000)).unwrap();
+        d.handle_press("Up", now + ms(2100)).unwrap();
+        assert_eq!(sent(&rx)[0], ("enter".to_string(), 0));
+    }
+
+    #[test]
+    fn diagonal_pointer_motion() {
+        let (mut d, rx) = dispatcher("  Up: mouse_up\n  Right: mouse_right\n  Select: enter\n");
+        let now = Instant::now();
+        d.handle_press("Up", now).unwrap();
+        d.handle_press("Right", now).unwrap();
+        for i in 0..50 {
+            d.tick(now + ms(i * 20)).unwrap();
+        }
+        let (x, y) = rx.try_iter().fold((0, 0), |(x, y), command| match command {
+            Command::MoveMouse(dx, dy) => (x + dx, y + dy),
+            other => panic!("unexpected command {:?}", other),
+        });
+        assert!(x > 0 && y > 0 && x == y, "moved {}, {}", x, y);
+
+        // Any other button stops the motion, in case a release frame was lost
+        d.handle_press("Select", now + ms(1000)).unwrap();
+        assert_eq!(d.pointer.next_deadline(), None);
+    }
+
+    #[test]
+    fn multi_tap_with_hold() {
+        let (mut d, rx) = dispatcher("  Select: { tap: enter, double: space, hold: esc, hold_ms: 400 }\n");
+        let now = Instant::now();
+
+        // The tap window stays open while the button may still become a long press
+        d.handle_press("Select", now).unwrap();
+        d.tick(now + ms(399)).unwrap();
+        assert!(sent(&rx).is_empty());
+        d.tick(now + ms(400)).unwrap();
+        assert_eq!(sent(&rx), [("esc".to_string(), 400)]);
+        d.handle_release("Select", ms(600)).unwrap();
+        d.tick(now + ms(1000)).unwrap();
+        assert!(sent(&rx).is_empty());
+
+        d.handle_press("Select", now + ms(2000)).unwrap();
+        d.handle_release("Select", ms(100)).unwrap();
+        d.tick(now + ms(2300)).unwrap();
+        assert_eq!(sent(&rx), [("enter".to_string(), 0)]);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::action::ShellCommand;
+use anyhow::Result;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+
+// Time a command waited for by exec(..., wait) may take before it is killed, unless it has its own timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run a command of an exec() or spawn() action with `sh -c`, adding `env` to its environment.
+///
+/// The command is started and waited for on a thread of its own, so it never holds up the
+/// input thread, and killed once its timeout passes. `then` is called when it has exited or
+/// failed to start. Output goes to the bridge's own stdout and stderr, so it ends up in the
+/// journal under systemd.
+pub fn run(exec: &ShellCommand, env: Vec<(&'static str, String)>, then: impl FnOnce() + Send + 'static) -> Result<()> {
+    let exec = exec.clone();
+    thread::Builder::new().name("exec".to_string()).spawn(move || {
+        match start(&exec, &env) {
+            Ok(child) => wait(child, &exec.command, exec.timeout.or(exec.wait.then_some(DEFAULT_TIMEOUT))),
+            Err(e) => error!("{}", e),
+        }
+        then();
+    })?;
+    Ok(())
+}
+
+fn start(exec: &ShellCommand, env: &[(&'static str, String)]) -> Result<Child> {
+    let mut command = Command::new("/bin/sh");
+    // Own process group, so a timeout also kills whatever the shell started
+    command.arg("-c").arg(&exec.command).stdin(Stdio::null()).process_group(0);
+    command.envs(env.iter().map(|(name, value)| (*name, value)));
+    if let Some(cwd) = &exec.cwd {
+        command.current_dir(cwd);
+    }
+    if let Some(user) = &exec.user {
+        let account = lookup_user(user)?;
+        command.env("USER", user).env("HOME", &account.home);
+        // Command::uid() would leave the command without the user's supplementary groups (audio,
+        // video, input...), so switch like login does: groups first, then the group and user
+        // SAFETY: setgroups, setgid and setuid are async-signal-safe and only use `account`
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setgroups(account.groups.len(), account.groups.as_ptr()) != 0
+                    || libc::setgid(account.gid) != 0
+                    || libc::setuid(account.uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    let child = command.spawn().map_err(|e| anyhow::anyhow!("Failed to run '{}': {}", exec.command, e))?;
+    debug!("Started '{}' as process {}", exec.command, child.id());
+    Ok(child)
+}
+
+fn wait(mut child: Child, name: &str, timeout: Option<Duration>) {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status = loop {
+        let Some(deadline) = deadline else {
+            break child.wait();
+        };
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if Instant::now() >= deadline => {
+                warn!("Command '{}' timed out after {} ms, killing it", name, timeout.unwrap_or_default().as_millis());
+                // SAFETY: plain kill(2) on the process group created for the child
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                break child.wait();
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => break Err(e),
+        }
+    };
+
+    match status {
+        Ok(status) if status.success() => debug!("Command '{}' finished", name),
+        Ok(status) => warn!("Command '{}' failed: {}", name, status),
+        Err(e) => error!("Failed to wait for command '{}': {}", name, e),
+    }
+}
+
+struct Account {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: String,
+    // Primary and supplementary groups, as initgroups() would set them
+    groups: Vec<libc::gid_t>,
+}
+
+// Ids, groups and home directory of a user name
+fn lookup_user(name: &str) -> Result<Account> {
+    let c_name = CString::new(name)?;
+    // SAFETY: passwd is plain data, all zeroes is a valid value
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+    // SAFETY: the buffers outlive the call and their sizes are passed along
+    let ret = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        anyhow::bail!("Unknown user '{}'", name);
+    }
+    // SAFETY: getpwnam_r succeeded, so pw_dir points to a string in `buf`
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) }.to_string_lossy().into_owned();
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: `groups` holds `count` entries; on -1 `count` is set to the number needed
+        let ret = unsafe { libc::getgrouplist(c_name.as_ptr(), pwd.pw_gid, groups.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            break;
+        }
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+    Ok(Account { uid: pwd.pw_uid, gid: pwd.pw_gid, home, groups })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn command(text: &str) -> ShellCommand {
+        match crate::action::Action::parse(text).unwrap() {
+            crate::action::Action::Exec(exec) => exec,
+            other => panic!("not a command: {}", other),
+        }
+    }
+
+    #[test]
+    fn runs_in_the_background() {
+        let (tx, rx) = mpsc::channel();
+        let started = Instant::now();
+        run(&command("exec('sleep 0.3', wait)"), Vec::new(), move || tx.send(()).unwrap()).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn timeout_kills_the_command() {
+        let (tx, rx) = mpsc::channel();
+        let started = Instant::now();
+        run(&command("exec('sleep 30', timeout=100ms)"), Vec::new(), move || tx.send(()).unwrap()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Commands that fail to start still let the sequence go on
+        let (tx, rx) = mpsc::channel();
+        run(&command("exec('true', user=no-such-user-here)"), Vec::new(), move || tx.send(()).unwrap()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
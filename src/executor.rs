@@ -0,0 +1,204 @@
+use anyhow::Result;
+use crate::action::Action;
+use crate::config::Config;
+use crate::exec;
+use crate::http::Http;
+use crate::linux::UInputDevice;
+use crate::kodi::Kodi;
+use crate::mpris::Mpris;
+use crate::mpv::Mpv;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{error, info, warn};
+
+/// Sends actions to the virtual device from a background thread.
+///
+/// Sleeps, hold times and key delays in long sequences would otherwise stall the CEC event
+/// loop. Commands run one at a time in the order they were queued, so a press queued behind a
+/// long sequence is sent once the sequence has finished. Actions that are not input events,
+/// such as exec(), mpris() or http(), run on the same thread in their place in a sequence, so
+/// in `a, sleep(300ms), exec("notify")` the command starts after `a` and the pause. Commands run
+/// on threads of their own; after an `exec(..., wait)` the rest of its sequence is queued again
+/// once the command has exited, so other input goes on meanwhile.
+pub struct Executor {
+    tx: Sender<Command>,
+}
+
+/// The CEC event actions run for, passed to exec() commands and http() requests
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub button: &'static str,
+    /// How long the button was held
+    pub duration: Duration,
+    /// Active mapping layer when the action was queued
+    pub mode: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum Command {
+    Send(Action, Trigger),
+    Press(Action, Trigger),
+    Release(Vec<u16>),
+    MoveMouse(i32, i32),
+    Scroll(i32, i32),
+    TypeChar(char),
+}
+
+impl Executor {
+    pub fn new(mut device: UInputDevice, config: &Config) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Command>();
+        let effects = Effects {
+            mpris: Mpris::new(config.mpris.clone())?,
+            mpv: Mpv::new(config.mpv.clone())?,
+            kodi: Kodi::new(config.kodi.clone())?,
+            http: Http::new()?,
+            queue: tx.clone(),
+        };
+        thread::Builder::new().name("input".to_string()).spawn(move || {
+            for command in rx {
+                let result = match command {
+                    Command::Send(action, trigger) => {
+                        let (action, mut rest) = split_after_wait(action);
+                        device.send_key(&action, &mut |a| effects.run(a, &trigger, &mut rest))
+                    }
+                    Command::Press(action, trigger) => {
+                        device.press_action(&action, &mut |a| effects.run(a, &trigger, &mut None)).map(|_| ())
+                    }
+                    Command::Release(keys) => device.release_keys(&keys),
+                    Command::MoveMouse(x, y) => device.move_mouse(x, y),
+                    Command::Scroll(x, y) => device.scroll(x, y),
+                    Command::TypeChar(c) => device.type_char(c),
+                };
+                if let Err(e) = result {
+                    error!("Failed to send input event: {}", e);
+                }
+            }
+        })?;
+        Ok(Self { tx })
+    }
+
+    pub fn send_key(&self, action: &Action, trigger: Trigger) -> Result<()> {
+        self.queue(Command::Send(action.clone(), trigger))
+    }
+
+    /// Queue a hold mode press, returning the codes to release when the CEC button is released
+    pub fn press_action(&self, action: &Action, trigger: Trigger) -> Result<Vec<u16>> {
+        let codes = action.held_keys().map(|keys| keys.iter().map(|k| k.code).collect()).unwrap_or_default();
+        self.queue(Command::Press(action.clone(), trigger))?;
+        Ok(codes)
+    }
+
+    pub fn release_keys(&self, keys: &[u16]) -> Result<()> {
+        self.queue(Command::Release(keys.to_vec()))
+    }
+
+    pub fn move_mouse(&self, x: i32, y: i32) -> Result<()> {
+        self.queue(Command::MoveMouse(x, y))
+    }
+
+    pub fn scroll(&self, x: i32, y: i32) -> Result<()> {
+        self.queue(Command::Scroll(x, y))
+    }
+
+    pub fn type_char(&self, c: char) -> Result<()> {
+        self.queue(Command::TypeChar(c))
+    }
+
+    fn queue(&self, command: Command) -> Result<()> {
+        self.tx.send(command).map_err(|_| anyhow::anyhow!("Input thread stopped"))
+    }
+
+    // An executor handing its commands to the caller instead of an input thread
+    #[cfg(test)]
+    pub(crate) fn channel() -> (Self, mpsc::Receiver<Command>) {
+        let (tx, rx) = mpsc::channel();
+        (Self { tx }, rx)
+    }
+}
+
+// Split a sequence after its first exec(..., wait), returning the actions to send now and the
+// ones to queue again once the command has exited
+fn split_after_wait(action: Action) -> (Action, Option<Action>) {
+    let Action::Sequence(mut items) = action else {
+        return (action, None);
+    };
+    let Some(i) = items.iter().position(|a| matches!(a, Action::Exec(exec) if exec.wait)) else {
+        return (Action::Sequence(items), None);
+    };
+    let rest = items.split_off(i + 1);
+    let rest = match rest.len() {
+        0 => None,
+        1 => rest.into_iter().next(),
+        _ => Some(Action::Sequence(rest)),
+    };
+    (Action::Sequence(items), rest)
+}
+
+// Runs the actions of a sequence that do not send input events. The clients queue their
+// commands to their own threads, so a slow player does not hold up the input thread.
+struct Effects {
+    mpris: Mpris,
+    mpv: Mpv,
+    kodi: Kodi,
+    http: Http,
+    // The input thread's own queue, for the rest of a sequence after exec(..., wait)
+    queue: Sender<Command>,
+}
+
+impl Effects {
+    fn run(&self, action: &Action, trigger: &Trigger, rest: &mut Option<Action>) -> Result<()> {
+        match action {
+            Action::Exec(command) => {
+                let env = vec![
+                    ("CEC_KEY", trigger.button.to_string()),
+                    ("CEC_DURATION_MS", trigger.duration.as_millis().to_string()),
+                    ("CEC_MODE", trigger.mode.clone()),
+                ];
+                info!("Running '{}' for CEC event '{}'", command.command, trigger.button);
+                let rest = if command.wait { rest.take() } else { None };
+                let (queue, trigger) = (self.queue.clone(), trigger.clone());
+                // A failing command must not stop the rest of the sequence, which goes to the back
+                // of the queue behind input that arrived meanwhile
+                let result = exec::run(command, env, move || {
+                    if let Some(rest) = rest {
+                        let _ = queue.send(Command::Send(rest, trigger));
+                    }
+                });
+                if let Err(e) = result {
+                    error!("Failed to run '{}': {}", command.command, e);
+                }
+            }
+            Action::Mpris(command, player) => self.mpris.send(*command, player.as_deref())?,
+            Action::Mpv(command) => self.mpv.send(command)?,
+            Action::Kodi(method, params) => self.kodi.send(method, params.as_ref())?,
+            Action::Http(request) => {
+                let vars = [
+                    ("button", trigger.button.to_string()),
+                    ("duration_ms", trigger.duration.as_millis().to_string()),
+                    ("mode", trigger.mode.clone()),
+                ];
+                self.http.send(request, &vars)?;
+            }
+            _ => warn!("Action '{}' cannot be sent to the input device", action),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_split_after_waiting_commands() {
+        let split = |text: &str| {
+            let (now, rest) = split_after_wait(Action::parse(text).unwrap());
+            (now.to_string(), rest.map(|a| a.to_string()))
+        };
+        assert_eq!(split("a, exec(\"x\"), b"), ("a, exec(\"x\"), b".to_string(), None));
+        assert_eq!(split("a, exec(\"x\", wait), b"), ("a, exec(\"x\", wait)".to_string(), Some("b".to_string())));
+        assert_eq!(split("exec(\"x\", wait), b, exec(\"y\", wait), c").1, Some("b, exec(\"y\", wait), c".to_string()));
+        assert_eq!(split("a, exec(\"x\", wait)"), ("a, exec(\"x\", wait)".to_string(), None));
+    }
+}
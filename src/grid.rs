@@ -0,0 +1,57 @@
+use log::debug;
+
+/// Grid jump pointer positioning on the number buttons.
+///
+/// The screen is split into a 3x3 grid laid out like the remote keypad, `Number1` being the
+/// top left cell. Picking a cell moves the pointer to its centre and splits that cell again,
+/// so a few presses reach any point. `Number0` goes back to the whole screen; any other
+/// button is handled normally and the next pick starts from the whole screen again.
+pub struct Grid {
+    active: bool,
+    // Left, top, width and height of the current cell as fractions of the screen
+    cell: (f64, f64, f64, f64),
+}
+
+const WHOLE_SCREEN: (f64, f64, f64, f64) = (0.0, 0.0, 1.0, 1.0);
+
+impl Grid {
+    pub fn new() -> Self {
+        Self { active: false, cell: WHOLE_SCREEN }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Enable or disable grid jumps, returning the screen centre to move to when enabled
+    pub fn set_active(&mut self, active: bool) -> Option<(f64, f64)> {
+        self.active = active;
+        self.cell = WHOLE_SCREEN;
+        if active { Some(self.centre()) } else { None }
+    }
+
+    /// Handle a press while grid jumps are active, returning where to move the pointer.
+    /// Returns None for buttons that are not part of the grid.
+    pub fn press(&mut self, button: &str) -> Option<(f64, f64)> {
+        let digit = button.strip_prefix("Number").and_then(|n| n.parse::<u8>().ok());
+        match digit {
+            Some(0) => self.cell = WHOLE_SCREEN,
+            Some(n @ 1..=9) => {
+                let (col, row) = (f64::from((n - 1) % 3), f64::from((n - 1) / 3));
+                let (x, y, w, h) = self.cell;
+                self.cell = (x + col * w / 3.0, y + row * h / 3.0, w / 3.0, h / 3.0);
+            }
+            _ => {
+                self.cell = WHOLE_SCREEN;
+                return None;
+            }
+        }
+        debug!("Grid cell now at {:?}", self.cell);
+        Some(self.centre())
+    }
+
+    fn centre(&self) -> (f64, f64) {
+        let (x, y, w, h) = self.cell;
+        (x + w / 2.0, y + h / 2.0)
+    }
+}
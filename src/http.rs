@@ -0,0 +1,176 @@
+use crate::action::HttpRequest;
+use anyhow::Result;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+
+// Time allowed for each attempt of requests without their own timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause before the first retry, doubling with every further one up to MAX_RETRY_DELAY
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Sends the requests of http() actions from a background thread, one after another, so slow
+/// endpoints and retries never hold up button handling.
+pub struct Http {
+    tx: Sender<HttpRequest>,
+}
+
+impl Http {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<HttpRequest>();
+        thread::Builder::new().name("http".to_string()).spawn(move || {
+            let agent = ureq::AgentBuilder::new().build();
+            for request in rx {
+                send(&agent, &request);
+            }
+        })?;
+        Ok(Self { tx })
+    }
+
+    /// Queue `request` with the `{name}` placeholders in its URL and body replaced from `vars`
+    pub fn send(&self, request: &HttpRequest, vars: &[(&str, String)]) -> Result<()> {
+        let render = |text: &str| {
+            vars.iter().fold(text.to_string(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+        };
+        let request = HttpRequest { url: render(&request.url), body: request.body.as_deref().map(render), ..request.clone() };
+        self.tx.send(request).map_err(|_| anyhow::anyhow!("HTTP thread stopped"))
+    }
+}
+
+fn send(agent: &ureq::Agent, request: &HttpRequest) {
+    for attempt in 0..=request.retries {
+        if attempt > 0 {
+            thread::sleep(retry_delay(attempt));
+        }
+        match send_once(agent, request) {
+            Ok(status) => {
+                debug!("{} {} returned {}", request.method, request.url, status);
+                return;
+            }
+            Err((e, retry)) if retry && attempt < request.retries => {
+                debug!("{} {} failed, trying again: {}", request.method, request.url, e);
+            }
+            Err((e, _)) => {
+                warn!("{} {} failed: {}", request.method, request.url, e);
+                return;
+            }
+        }
+    }
+}
+
+// Pause before the given attempt, counting the first retry as attempt 1
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(MAX_RETRY_DELAY)
+}
+
+// The response status, or the error and whether trying again may help
+fn send_once(agent: &ureq::Agent, request: &HttpRequest) -> Result<u16, (String, bool)> {
+    let call = agent.request(&request.method, &request.url).timeout(request.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let result = match &request.body {
+        Some(body) => {
+            let json = body.trim_start().starts_with(['{', '[']);
+            call.set("Content-Type", if json { "application/json" } else { "text/plain; charset=utf-8" })
+                .send_string(body)
+        }
+        None => call.call(),
+    };
+    match result {
+        Ok(response) => Ok(response.status()),
+        // Server errors and rate limiting may pass, other client errors will not
+        Err(ureq::Error::Status(status, _)) => Err((format!("status {}", status), status >= 500 || status == 429)),
+        Err(e) => Err((e.to_string(), true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // Answer one request per connection with each status in turn, returning the request lines,
+    // content types and bodies
+    fn serve(listener: TcpListener, statuses: &'static [u16]) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            statuses.iter().map(|status| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut request = vec![line.trim().to_string()];
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim().to_lowercase();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = header.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if header.starts_with("content-type:") {
+                        request.push(header);
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                request.push(String::from_utf8(body).unwrap());
+                request.join(", ")
+            }).collect()
+        })
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve(listener, &[503, 200, 404]);
+
+        let agent = ureq::agent();
+        let request = |method: &str, path: &str, body: Option<&str>| HttpRequest {
+            method: method.to_string(),
+            url: format!("{}{}", url, path),
+            body: body.map(str::to_string),
+            timeout: None,
+            retries: 2,
+        };
+        send(&agent, &request("POST", "/event", Some("{\"button\": \"Up\"}")));
+        // Client errors other than 429 are not retried
+        send(&agent, &request("GET", "/missing", None));
+
+        assert_eq!(server.join().unwrap(), [
+            "POST /event HTTP/1.1, content-type: application/json, {\"button\": \"Up\"}",
+            "POST /event HTTP/1.1, content-type: application/json, {\"button\": \"Up\"}",
+            "GET /missing HTTP/1.1, ",
+        ]);
+    }
+
+    #[test]
+    fn retry_delays() {
+        let delays: Vec<u64> = (1..=7).map(|attempt| retry_delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn placeholders() {
+        let (tx, rx) = mpsc::channel();
+        let http = Http { tx };
+        let request = HttpRequest {
+            method: "POST".to_string(),
+            url: "http://hub/{button}?mode={mode}".to_string(),
+            body: Some("{button} {duration_ms} {other}".to_string()),
+            timeout: None,
+            retries: 0,
+        };
+        let vars = [("button", "Up".to_string()), ("duration_ms", "120".to_string()), ("mode", "tv".to_string())];
+        http.send(&request, &vars).unwrap();
+        let sent = rx.recv().unwrap();
+        assert_eq!(sent.url, "http://hub/Up?mode=tv");
+        assert_eq!(sent.body.as_deref(), Some("Up 120 {other}"));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{KodiConfig, KodiTransport};
+use anyhow::Result;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{json, Deserializer, Value};
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+
+// Player.* methods that do not act on a single player, so get no playerid filled in
+const PLAYERLESS_METHODS: &[&str] =
+    &["Player.GetActivePlayers", "Player.GetPlayers", "Player.Open", "Player.GetViewMode", "Player.SetViewMode"];
+
+/// Calls Kodi JSON-RPC methods for kodi() actions from a background thread.
+///
+/// `Player.*` methods without a `playerid` parameter act on the active player. Over TCP the
+/// connection is kept open, and opened again when Kodi has closed it.
+pub struct Kodi {
+    tx: Sender<(String, Option<Value>)>,
+}
+
+impl Kodi {
+    pub fn new(config: KodiConfig) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<(String, Option<Value>)>();
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_millis(config.timeout_ms)).build();
+        thread::Builder::new().name("kodi".to_string()).spawn(move || {
+            let mut client = Client { config, agent, stream: None, request_id: 0 };
+            for (method, params) in rx {
+                if let Err(e) = client.run(&method, params) {
+                    warn!("Kodi method {} failed: {}", method, e);
+                }
+            }
+        })?;
+        Ok(Self { tx })
+    }
+
+    pub fn send(&self, method: &str, params: Option<&Value>) -> Result<()> {
+        self.tx.send((method.to_string(), params.cloned())).map_err(|_| anyhow::anyhow!("Kodi thread stopped"))
+    }
+}
+
+struct Client {
+    config: KodiConfig,
+    agent: ureq::Agent,
+    stream: Option<BufReader<TcpStream>>,
+    request_id: u64,
+}
+
+impl Client {
+    fn run(&mut self, method: &str, params: Option<Value>) -> Result<()> {
+        let mut params = params.unwrap_or_else(|| json!({}));
+        let needs_player = method.starts_with("Player.")
+            && !PLAYERLESS_METHODS.contains(&method)
+            && params.as_object().is_some_and(|params| !params.contains_key("playerid"));
+        if needs_player {
+            let players = self.call("Player.GetActivePlayers", json!({}))?;
+            let Some(id) = players.get(0).and_then(|player| player.get("playerid")).cloned() else {
+                anyhow::bail!("no active player");
+            };
+            params["playerid"] = id;
+        }
+        let result = self.call(method, params)?;
+        debug!("Kodi method {} returned {}", method, result);
+        Ok(())
+    }
+
+    // Send one request and return its result, or Kodi's error as an error
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.request_id += 1;
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": self.request_id });
+        let mut response = match self.config.transport {
+            KodiTransport::Http => self.post(&request)?,
+            KodiTransport::Tcp => {
+                let reused = self.stream.is_some();
+                match self.exchange(&request) {
+                    Ok(response) => response,
+                    // A connection left over from before a Kodi restart only fails on use, and
+                    // the request never reached the new Kodi, so it is safe to send again
+                    Err(e) if reused && is_closed(&e) => {
+                        debug!("Kodi closed the connection ({}), reconnecting", e);
+                        self.stream = None;
+                        self.exchange(&request).inspect_err(|_| self.stream = None)?
+                    }
+                    Err(e) => {
+                        self.stream = None;
+                        return Err(e.into());
+                    }
+                }
+            }
+        };
+
+        if let Some(error) = response.get("error") {
+            let mut message = error["message"].as_str().unwrap_or("unknown error").to_string();
+            // Invalid params name the offending parameter here
+            let stack = &error["data"]["stack"];
+            if let (Some(name), Some(detail)) = (stack["name"].as_str(), stack["message"].as_str()) {
+                message.push_str(&format!(" {}: {}", name, detail));
+            }
+            anyhow::bail!("{}", message);
+        }
+        Ok(response["result"].take())
+    }
+
+    fn post(&self, request: &Value) -> Result<Value> {
+        let url = format!("http://{}:{}/jsonrpc", self.config.host, self.config.port.unwrap_or(8080));
+        let mut post = self.agent.post(&url);
+        if let Some(username) = &self.config.username {
+            let credentials = format!("{}:{}", username, self.config.password.as_deref().unwrap_or_default());
+            post = post.set("Authorization", &format!("Basic {}", BASE64_STANDARD.encode(credentials)));
+        }
+        let response = post.send_json(request).map_err(|e| match e {
+            ureq::Error::Status(401, _) => anyhow::anyhow!("Kodi rejected the username or password"),
+            e => anyhow::anyhow!("{}", e),
+        })?;
+        Ok(response.into_json()?)
+    }
+
+    fn exchange(&mut self, request: &Value) -> io::Result<Value> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                let port = self.config.port.unwrap_or(9090);
+                let timeout = Duration::from_millis(self.config.timeout_ms);
+                let address = (self.config.host.as_str(), port).to_socket_addrs()?.next()
+                    .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("unknown host {}", self.config.host)))?;
+                let stream = TcpStream::connect_timeout(&address, timeout)?;
+                stream.set_read_timeout(Some(timeout))?;
+                debug!("Connected to Kodi at {}:{}", self.config.host, port);
+                self.stream.insert(BufReader::new(stream))
+            }
+        };
+
+        stream.get_mut().write_all(request.to_string().as_bytes())?;
+        // Messages are JSON values without separators; notifications such as Player.OnPlay
+        // arrive on the same connection and are skipped
+        for message in Deserializer::from_reader(&mut *stream).into_iter::<Value>() {
+            let message = message?;
+            if message.get("id") == Some(&request["id"]) {
+                return Ok(message);
+            }
+        }
+        Err(ErrorKind::UnexpectedEof.into())
+    }
+}
+
+fn is_closed(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // Answer the requests on one connection with the given responses, then close it. Each
+    // response follows a notification and is written in two halves, as Kodi's messages have
+    // no separators and may arrive in pieces.
+    fn serve(listener: &TcpListener, responses: &[Value]) -> Vec<Value> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let reader = stream.try_clone().unwrap();
+        let mut requests = Deserializer::from_reader(reader).into_iter::<Value>();
+        let mut received = Vec::new();
+        for response in responses {
+            let request = requests.next().unwrap().unwrap();
+            let mut response = response.clone();
+            response["id"] = request["id"].clone();
+            let notification = json!({ "jsonrpc": "2.0", "method": "Player.OnPause", "params": {} });
+            let text = format!("{}{}", notification, response);
+            let (first, second) = text.split_at(text.len() - 5);
+            stream.write_all(first.as_bytes()).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(second.as_bytes()).unwrap();
+            received.push(request);
+        }
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        received
+    }
+
+    #[test]
+    fn tcp_framing_and_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = serve(&listener, &[
+                json!({ "jsonrpc": "2.0", "result": [{ "playerid": 1, "type": "video" }] }),
+                json!({ "jsonrpc": "2.0", "result": { "speed": 0 } }),
+                json!({ "jsonrpc": "2.0", "error": { "code": -32602, "message": "Invalid params.",
+                    "data": { "stack": { "name": "action", "message": "Received value does not match" } } } }),
+            ]);
+            // Kodi restarted, the client has to notice the closed connection and send again
+            requests.extend(serve(&listener, &[json!({ "jsonrpc": "2.0", "result": "OK" })]));
+            requests
+        });
+
+        let config = KodiConfig { transport: KodiTransport::Tcp, host: "127.0.0.1".to_string(), port: Some(port), ..Default::default() };
+        let mut client = Client { config, agent: ureq::agent(), stream: None, request_id: 0 };
+        client.run("Player.PlayPause", None).unwrap();
+        let error = client.run("Input.ExecuteAction", Some(json!({ "action": "bogus" }))).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid params. action: Received value does not match");
+        // Give the old connection time to close
+        thread::sleep(Duration::from_millis(50));
+        client.run("Input.Select", None).unwrap();
+
+        let requests = server.join().unwrap();
+        let methods: Vec<_> = requests.iter().map(|r| (r["method"].as_str().unwrap(), r["params"].clone())).collect();
+        assert_eq!(methods, [
+            ("Player.GetActivePlayers", json!({})),
+            ("Player.PlayPause", json!({ "playerid": 1 })),
+            ("Input.ExecuteAction", json!({ "action": "bogus" })),
+            ("Input.Select", json!({})),
+        ]);
+        assert_eq!(requests.iter().map(|r| r["id"].as_u64().unwrap()).collect::<Vec<_>>(), [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::Layout;
+use mouse_keyboard_input::key_codes::*;
+
+/// A key press producing a character, with the modifiers it needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub code: u16,
+    pub shift: bool,
+    pub altgr: bool,
+}
+
+// Printable keys of a pc105 keyboard by row, in the order of the layout strings below
+const ROWS: [&[u16]; 4] = [
+    &[KEY_GRAVE, KEY_1, KEY_2, KEY_3, KEY_4, KEY_5, KEY_6, KEY_7, KEY_8, KEY_9, KEY_10, KEY_MINUS, KEY_EQUAL],
+    &[KEY_Q, KEY_W, KEY_E, KEY_R, KEY_T, KEY_Y, KEY_U, KEY_I, KEY_O, KEY_P, KEY_LEFTBRACE, KEY_RIGHTBRACE],
+    &[KEY_A, KEY_S, KEY_D, KEY_F, KEY_G, KEY_H, KEY_J, KEY_K, KEY_L, KEY_SEMICOLON, KEY_APOSTROPHE, KEY_BACKSLASH],
+    &[KEY_102ND, KEY_Z, KEY_X, KEY_C, KEY_V, KEY_B, KEY_N, KEY_M, KEY_COMMA, KEY_DOT, KEY_SLASH],
+];
+
+// Characters of each row without modifiers, with shift and with AltGr, following the XKB
+// layouts of the same name. NUL marks keys without a character on that level, including dead keys.
+struct Levels {
+    plain: [&'static str; 4],
+    shift: [&'static str; 4],
+    altgr: [&'static str; 4],
+}
+
+const US: Levels = Levels {
+    plain: ["`1234567890-=", "qwertyuiop[]", "asdfghjkl;'\\", "\x00zxcvbnm,./"],
+    shift: ["~!@#$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:\"|", "\x00ZXCVBNM<>?"],
+    altgr: ["", "", "", ""],
+};
+
+const GB: Levels = Levels {
+    plain: ["`1234567890-=", "qwertyuiop[]", "asdfghjkl;'#", "\\zxcvbnm,./"],
+    shift: ["¬!\"£$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:@~", "|ZXCVBNM<>?"],
+    altgr: ["¦\x00\x00\x00€", "", "", ""],
+};
+
+const DE: Levels = Levels {
+    plain: ["\x001234567890ß\x00", "qwertzuiopü+", "asdfghjklöä#", "<yxcvbnm,.-"],
+    shift: ["°!\"§$%&/()=?\x00", "QWERTZUIOPÜ*", "ASDFGHJKLÖÄ'", ">YXCVBNM;:_"],
+    altgr: ["\x00¹²³¼½¬{[]}\\", "@\x00€\x00\x00\x00\x00\x00\x00\x00\x00~", "", "|\x00\x00\x00\x00\x00\x00µ"],
+};
+
+const FR: Levels = Levels {
+    plain: ["²&é\"'(-è_çà)=", "azertyuiop\x00$", "qsdfghjklmù*", "<wxcvbn,;:!"],
+    shift: ["\x001234567890°+", "AZERTYUIOP\x00£", "QSDFGHJKLM%µ", ">WXCVBN?./§"],
+    altgr: ["\x00\x00\x00#{[|\x00\\\x00@]}", "\x00\x00€\x00\x00\x00\x00\x00\x00\x00\x00¤", "", ""],
+};
+
+/// Key press typing `c` on `layout`, None if the layout has no key for it
+pub fn char_key(layout: Layout, c: char) -> Option<KeyStroke> {
+    let plain = |code| Some(KeyStroke { code, shift: false, altgr: false });
+    match c {
+        ' ' => return plain(KEY_SPACE),
+        '\n' => return plain(KEY_ENTER),
+        '\t' => return plain(KEY_TAB),
+        '\x00' => return None,
+        _ => {}
+    }
+
+    let levels = match layout {
+        Layout::Us => &US,
+        Layout::Gb => &GB,
+        Layout::De => &DE,
+        Layout::Fr => &FR,
+    };
+    let find = |rows: &[&str; 4]| {
+        rows.iter().zip(ROWS).find_map(|(chars, codes)| {
+            chars.chars().position(|k| k == c).and_then(|i| codes.get(i).copied())
+        })
+    };
+
+    if let Some(code) = find(&levels.plain) {
+        return Some(KeyStroke { code, shift: false, altgr: false });
+    }
+    if let Some(code) = find(&levels.shift) {
+        return Some(KeyStroke { code, shift: true, altgr: false });
+    }
+    find(&levels.altgr).map(|code| KeyStroke { code, shift: false, altgr: true })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUTS: [(Layout, &Levels); 4] = [(Layout::Us, &US), (Layout::Gb, &GB), (Layout::De, &DE), (Layout::Fr, &FR)];
+
+    #[test]
+    fn rows_fit_the_keyboard() {
+        for (layout, levels) in LAYOUTS {
+            for rows in [&levels.plain, &levels.shift, &levels.altgr] {
+                for (chars, codes) in rows.iter().zip(ROWS) {
+                    assert!(chars.chars().count() <= codes.len(), "{:?}: '{}' is longer than its row", layout, chars);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn characters_type_themselves() {
+        // Each character must map back to the key it is listed under, so none is listed twice
+        for (layout, levels) in LAYOUTS {
+            for (level, rows) in [levels.plain, levels.shift, levels.altgr].iter().enumerate() {
+                for (chars, codes) in rows.iter().zip(ROWS) {
+                    for (c, code) in chars.chars().zip(codes).filter(|(c, _)| *c != '\x00') {
+                        let stroke = char_key(layout, c).unwrap();
+                        let found = (stroke.code, stroke.shift, stroke.altgr);
+                        assert_eq!(found, (*code, level == 1, level == 2), "{:?}: '{}'", layout, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layout_specific_keys() {
+        let key = |layout, c| char_key(layout, c).map(|k| (k.code, k.shift, k.altgr));
+        assert_eq!(key(Layout::Us, '@'), Some((KEY_2, true, false)));
+        assert_eq!(key(Layout::Gb, '@'), Some((KEY_APOSTROPHE, true, false)));
+        assert_eq!(key(Layout::De, '@'), Some((KEY_Q, false, true)));
+        assert_eq!(key(Layout::Fr, '@'), Some((KEY_10, false, true)));
+        assert_eq!(key(Layout::De, 'z'), Some((KEY_Y, false, false)));
+        assert_eq!(key(Layout::Fr, '1'), Some((KEY_1, true, false)));
+        assert_eq!(key(Layout::Gb, '\\'), Some((KEY_102ND, false, false)));
+        assert_eq!(key(Layout::De, '\n'), Some((KEY_ENTER, false, false)));
+        assert_eq!(key(Layout::Us, 'é'), None);
+        assert_eq!(key(Layout::Us, '\x00'), None);
+    }
+}
@@ -1,275 +1,301 @@
 use anyhow::Result;
-use mouse_keyboard_input::VirtualDevice;
 use mouse_keyboard_input::key_codes::*;
-use crate::Config;
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use crate::action::Action;
+use crate::config::{Config, KeyboardConfig, UnicodeInput};
+use crate::keys;
+use crate::layout::{self, KeyStroke};
+use crate::uinput::{VirtualDevice, KEY_MAX, WHEEL_NOTCH};
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
 use log::{debug, warn};
 
+// Buttons left out when declaring every key for MQTT commands: joystick, gamepad and tablet
+// buttons (BTN_JOYSTICK to BTN_GEAR_UP), the gamepad d-pad and BTN_TRIGGER_HAPPY*. With
+// them udev would tag the device as a joystick or tablet, and libinput would ignore it.
+const NON_KEYBOARD_BUTTONS: [RangeInclusive<u16>; 3] = [0x120..=0x15f, 0x220..=0x223, 0x2c0..=0x2e7];
+
 pub struct UInputDevice {
     device: VirtualDevice,
-    // counters for exponential mouse movement per axis+direction (keys like "x+", "x-", "y+", "y-")
-    move_counters: HashMap<String, u8>,
-    last_move: Option<Instant>,
+    // absolute pointer for pointer_to() and grid jumps, only created when the mappings use them
+    absolute: Option<VirtualDevice>,
+    // distance in pixels for a single mouse movement action
+    mouse_step: i32,
+    // notches for a single scroll action
+    scroll_step: i32,
+    keyboard: KeyboardConfig,
+    // pause between the keys of a sequence, unless overridden with delay()
+    key_delay: Duration,
+    // Keys currently down with the number of actions holding each, in the order they were pressed.
+    // A key is only released once nothing holds it any more, so a chord cannot let go of a sticky modifier.
+    held: Vec<(u16, u32)>,
+    // Keys held by press() and toggle()
+    sticky: Vec<u16>,
 }
 
 impl UInputDevice {
-    pub fn new(_config: &Config) -> Result<Self> {
-        let device = VirtualDevice::default().map_err(|e| anyhow::anyhow!("{}", e))?;
-        Ok(Self { device, move_counters: HashMap::new(), last_move: None })
+    pub fn new(config: &Config) -> Result<Self> {
+        // Standard keyboard and mouse keys, plus the media, TV and raw codes used by the mappings.
+        // MQTT commands may use any key and pointer_to(), so with a command topic the device
+        // declares every key and gets the absolute pointer.
+        let commands = config.mqtt.as_ref().is_some_and(|mqtt| mqtt.command_topic.is_some());
+        let mut codes: BTreeSet<u16> = if commands {
+            (1..=KEY_MAX).filter(|code| !NON_KEYBOARD_BUTTONS.iter().any(|r| r.contains(code))).collect()
+        } else {
+            (KEY_ESC..=KEY_MICMUTE).chain(BTN_LEFT..=BTN_TASK).collect()
+        };
+        let mut absolute = commands;
+        let layers = config.layers.values().flat_map(|layer| layer.values());
+        for mapping in config.mappings.values().chain(layers) {
+            for action in mapping.actions() {
+                action.walk(&mut |a| {
+                    codes.extend(a.keys().iter().map(|k| k.code));
+                    absolute |= matches!(a, Action::PointerTo(..) | Action::Grid(_));
+                });
+            }
+        }
+
+        let device = VirtualDevice::new(&config.device_name, codes, config.scroll.hi_res).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let absolute = if absolute {
+            let name = format!("{} pointer", config.device_name);
+            Some(VirtualDevice::absolute(&name).map_err(|e| anyhow::anyhow!("{}", e))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            device,
+            absolute,
+            mouse_step: config.mouse.step,
+            scroll_step: config.scroll.step,
+            keyboard: config.keyboard.clone(),
+            key_delay: Duration::from_millis(config.key_delay_ms),
+            held: Vec::new(),
+            sticky: Vec::new(),
+        })
     }
 
-    pub fn send_key(&mut self, action: &str) -> Result<()> {
-        // New parser that supports sequences (comma-separated), bracketed lists, and simultaneous keys (+)
+    // Send an action; actions that are not input events, such as exec(), are handed to `effects`
+    // in their place, so they run after the keys before them and any sleeps
+    pub fn send_key(&mut self, action: &Action, effects: &mut dyn FnMut(&Action) -> Result<()>) -> Result<()> {
         debug!("Sending key: {}", action);
-        let s = action.trim();
-        // Split top-level sequence items by ',' (e.g. "CTRL[c], enter")
-        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
-            let part = part.to_lowercase();
+        self.run(action, self.key_delay, effects)
+    }
 
-            // If this is a mouse mapped action, handle separately (prefix "mouse_" or exact names)
-            if part.starts_with("mouse_") {
-                self.send_mouse(&part)?;
-                continue;
+    // Send an action, pausing `delay` between the keys and items of sequences
+    fn run(&mut self, action: &Action, delay: Duration, effects: &mut dyn FnMut(&Action) -> Result<()>) -> Result<()> {
+        match action {
+            Action::Sequence(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 { thread::sleep(delay); }
+                    self.run(item, delay, effects)?;
+                }
             }
-
-            // Handle modifier with bracketed list, e.g. "alt[a,f]"
-            if let Some(idx) = part.find('[') {
-                if part.ends_with(']') {
-                    let mod_name = part[..idx].trim();
-                    let inner = &part[idx + 1..part.len() - 1];
-                    // Hold modifier across the whole bracketed list (press once, send all keys, release once)
-                    if let Some(mod_key) = Self::modifier_key(mod_name) {
-                        self.device.press(mod_key).map_err(|e| anyhow::anyhow!("{}", e))?;
-                        for sub in inner.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
-                            if let Some(k) = Self::key_from_name(sub) {
-                                self.device.click(k).map_err(|e| anyhow::anyhow!("{}", e))?;
-                            } else {
-                                warn!("Unknown action: {}", sub);
-                            }
-                        }
-                        self.device.release(mod_key).map_err(|e| anyhow::anyhow!("{}", e))?;
-                    } else {
-                        warn!("Unknown modifier: {}", mod_name);
-                    }
-                    continue;
+            Action::Chord(keys) => {
+                // Press all keys (modifiers first), then release in reverse order
+                for k in keys { self.key_down(k.code)?; }
+                for k in keys.iter().rev() { self.key_up(k.code)?; }
+            }
+            Action::WithKeys(keys, inner) => {
+                // Hold keys across the whole bracketed list (press once, send all actions, release once)
+                for k in keys { self.key_down(k.code)?; }
+                thread::sleep(delay);
+                self.run(inner, delay, effects)?;
+                thread::sleep(delay);
+                for k in keys.iter().rev() { self.key_up(k.code)?; }
+            }
+            Action::Hold(keys, duration) => {
+                for k in keys { self.key_down(k.code)?; }
+                thread::sleep(*duration);
+                for k in keys.iter().rev() { self.key_up(k.code)?; }
+            }
+            Action::Repeat(inner, count) => {
+                for i in 0..*count {
+                    if i > 0 { thread::sleep(delay); }
+                    self.run(inner, delay, effects)?;
                 }
             }
-
-            // Handle simultaneous keys with '+', e.g. "ctrl+alt+del"
-            if part.contains('+') {
-                let tokens: Vec<&str> = part.split('+').map(|t| t.trim()).filter(|t| !t.is_empty()).collect();
-                let mut mods: Vec<u16> = Vec::new();
-                let mut others: Vec<u16> = Vec::new();
-                for tok in tokens {
-                    if let Some(mk) = Self::modifier_key(tok) {
-                        mods.push(mk);
-                    } else if let Some(k) = Self::key_from_name(tok) {
-                        others.push(k);
-                    } else {
-                        warn!("Unknown action: {}", tok);
-                    }
+            Action::Delay(delay, inner) => self.run(inner, *delay, effects)?,
+            Action::Sleep(duration) => thread::sleep(*duration),
+            Action::Press(keys) => {
+                for k in keys { self.press_sticky(k.code)?; }
+            }
+            Action::Release(keys) => {
+                for k in keys.iter().rev() { self.release_sticky(k.code)?; }
+            }
+            Action::Toggle(keys) => {
+                // A chord toggles as a whole, by its first key
+                if keys.first().is_some_and(|k| self.sticky.contains(&k.code)) {
+                    for k in keys.iter().rev() { self.release_sticky(k.code)?; }
+                } else {
+                    for k in keys { self.press_sticky(k.code)?; }
                 }
-                // press modifiers
-                for mk in &mods { self.device.press(*mk).map_err(|e| anyhow::anyhow!("{}", e))?; }
-                // press other keys while modifiers are held
-                for k in &others { self.device.click(*k).map_err(|e| anyhow::anyhow!("{}", e))?; }
-                // release modifiers
-                for mk in &mods { self.device.release(*mk).map_err(|e| anyhow::anyhow!("{}", e))?; }
-                continue;
             }
-
-            // Default: single key name
-            if let Some(k) = Self::key_from_name(&part) {
-                self.device.click(k).map_err(|e| anyhow::anyhow!("{}", e))?;
-            } else {
-                warn!("Unknown action: {}", part);
+            Action::ReleaseAll => self.release_all()?,
+            Action::Text(text) => {
+                for (i, c) in text.chars().enumerate() {
+                    if i > 0 { thread::sleep(delay); }
+                    self.type_char(c)?;
+                }
+            }
+            Action::MouseMove(x, y) => {
+                // a single movement moves by the configured step; held buttons move continuously (see pointer.rs)
+                self.move_mouse(x * self.mouse_step, y * self.mouse_step)?;
             }
+            Action::PointerTo(x, y) => self.pointer_to(*x, *y)?,
+            Action::Scroll(x, y) => {
+                // held scroll buttons scroll continuously as well (see pointer.rs)
+                let units = self.scroll_step * WHEEL_NOTCH;
+                self.scroll(x * units, y * units)?;
+            }
+            _ => effects(action)?,
         }
 
         Ok(())
     }
 
-    // send mouse events based on mapping names like:
-    // mouse_right, mouse_left, mouse_up, mouse_down, mouse_click_left, mouse_click_right
-    // movement uses exponential steps: [1,10,50,100,500] and resets after 500ms idle
-    fn send_mouse(&mut self, action: &str) -> Result<()> {
-        // reset counters if idle > 500ms
-        let now = Instant::now();
-        if let Some(last) = self.last_move {
-            if now.duration_since(last) > Duration::from_millis(500) {
-                self.move_counters.clear();
-            }
-        }
-        self.last_move = Some(now);
+    // Press a single chord (e.g. "ctrl+c", "up", "mouse_click_left") and keep it held.
+    // Returns the pressed codes so they can be released when the CEC button is released.
+    // Actions that cannot be held (sequences, bracketed lists, mouse movement) are sent as a normal click.
+    pub fn press_action(&mut self, action: &Action, effects: &mut dyn FnMut(&Action) -> Result<()>) -> Result<Vec<u16>> {
+        let Some(keys) = action.held_keys() else {
+            self.send_key(action, effects)?;
+            return Ok(Vec::new());
+        };
 
-        // movement steps
-        let steps = [1, 10, 50, 100, 500];
+        debug!("Pressing key: {}", action);
+        let codes: Vec<u16> = keys.iter().map(|k| k.code).collect();
+        for k in &codes { self.key_down(*k)?; }
+        Ok(codes)
+    }
 
-        match action {
-            "mouse_right" => {
-                let key = "x+".to_string();
-                let cnt = self.move_counters.entry(key.clone()).or_insert(0);
-                if *cnt < steps.len() as u8 { *cnt += 1; }
-                let idx = (*cnt as usize).saturating_sub(1).min(steps.len() - 1);
-                let delta = steps[idx] as i32;
-                self.device.move_mouse(delta, 0).map_err(|e| anyhow::anyhow!("{}", e))?;
-            }
-            "mouse_left" => {
-                let key = "x-".to_string();
-                let cnt = self.move_counters.entry(key.clone()).or_insert(0);
-                if *cnt < steps.len() as u8 { *cnt += 1; }
-                let idx = (*cnt as usize).saturating_sub(1).min(steps.len() - 1);
-                let delta = -(steps[idx] as i32);
-                self.device.move_mouse(delta, 0).map_err(|e| anyhow::anyhow!("{}", e))?;
-            }
-            "mouse_up" => {
-                let key = "y+".to_string();
-                let cnt = self.move_counters.entry(key.clone()).or_insert(0);
-                if *cnt < steps.len() as u8 { *cnt += 1; }
-                let idx = (*cnt as usize).saturating_sub(1).min(steps.len() - 1);
-                let delta = steps[idx] as i32;
-                self.device.move_mouse(0, delta).map_err(|e| anyhow::anyhow!("{}", e))?;
-            }
-            "mouse_down" => {
-                let key = "y-".to_string();
-                let cnt = self.move_counters.entry(key.clone()).or_insert(0);
-                if *cnt < steps.len() as u8 { *cnt += 1; }
-                let idx = (*cnt as usize).saturating_sub(1).min(steps.len() - 1);
-                let delta = -(steps[idx] as i32);
-                self.device.move_mouse(0, delta).map_err(|e| anyhow::anyhow!("{}", e))?;
-            }
-            "mouse_click_left" | "mouse_left_click" | "mouse_lclick" => {
-                self.device.click(BTN_LEFT).map_err(|e| anyhow::anyhow!("{}", e))?;
-            }
-            "mouse_click_right" | "mouse_right_click" | "mouse_rclick" => {
-                self.device.click(BTN_RIGHT).map_err(|e| anyhow::anyhow!("{}", e))?;
+    // Release codes returned by press_action, in reverse order so modifiers are let go last
+    pub fn release_keys(&mut self, keys: &[u16]) -> Result<()> {
+        for k in keys.iter().rev() { self.key_up(*k)?; }
+        Ok(())
+    }
+
+    // Resolve a key, modifier or mouse button name used in actions to its code
+    pub fn key_code(name: &str) -> Option<u16> {
+        keys::code(name)
+    }
+
+    pub fn is_modifier(code: u16) -> bool {
+        matches!(code,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL | KEY_LEFTALT | KEY_RIGHTALT |
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT | KEY_LEFTMETA | KEY_RIGHTMETA)
+    }
+
+    // Type a single character on the configured keyboard layout, pressing shift or AltGr where
+    // needed, or with the Unicode input method for characters the layout has no key for
+    pub fn type_char(&mut self, c: char) -> Result<()> {
+        if let Some(stroke) = layout::char_key(self.keyboard.layout, c) {
+            return self.type_stroke(stroke);
+        }
+
+        match self.keyboard.unicode_input {
+            UnicodeInput::None => {
+                warn!("Cannot type character {:?} on the {:?} keyboard layout", c, self.keyboard.layout);
             }
-            _ => {
-                warn!("Unknown mouse action: {}", action);
+            UnicodeInput::CtrlShiftU => {
+                debug!("Typing {:?} as Unicode code point U+{:04X}", c, c as u32);
+                for code in [KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_U] { self.key_down(code)?; }
+                for code in [KEY_U, KEY_LEFTSHIFT, KEY_LEFTCTRL] { self.key_up(code)?; }
+                for digit in format!("{:x}", c as u32).chars() {
+                    if let Some(stroke) = layout::char_key(self.keyboard.layout, digit) {
+                        self.type_stroke(stroke)?;
+                    }
+                }
+                self.click(KEY_SPACE)?;
             }
         }
+        Ok(())
+    }
+
+    fn type_stroke(&mut self, stroke: KeyStroke) -> Result<()> {
+        if stroke.altgr { self.key_down(KEY_RIGHTALT)?; }
+        if stroke.shift { self.key_down(KEY_LEFTSHIFT)?; }
+        self.click(stroke.code)?;
+        if stroke.shift { self.key_up(KEY_LEFTSHIFT)?; }
+        if stroke.altgr { self.key_up(KEY_RIGHTALT)?; }
+        Ok(())
+    }
 
+    // Release every held key, most recently pressed first
+    pub fn release_all(&mut self) -> Result<()> {
+        self.sticky.clear();
+        while let Some((code, _)) = self.held.pop() {
+            debug!("Releasing held key {}", code);
+            self.device.release(code).map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
         Ok(())
     }
 
-    // Helper: map modifier names to keys
-    fn modifier_key(name: &str) -> Option<u16> {
-        match name.trim() {
-            "ctrl" | "control" | "lctrl" | "leftctrl" => Some(KEY_LEFTCTRL),
-            "rctrl" | "rightctrl" => Some(KEY_RIGHTCTRL),
-            "alt" | "lalt" | "leftalt" => Some(KEY_LEFTALT),
-            "ralt" | "rightalt" => Some(KEY_RIGHTALT),
-            "shift" | "lshift" | "leftshift" => Some(KEY_LEFTSHIFT),
-            "rshift" | "rightshift" => Some(KEY_RIGHTSHIFT),
-            "meta" | "super" | "lmeta" | "leftmeta" => Some(KEY_LEFTMETA),
-            "rmeta" | "rightmeta" => Some(KEY_RIGHTMETA),
-            _ => None,
+    fn press_sticky(&mut self, code: u16) -> Result<()> {
+        if !self.sticky.contains(&code) {
+            self.sticky.push(code);
+            self.key_down(code)?;
         }
+        Ok(())
     }
 
-    // Helper: map name strings to key codes (extracted from previous single-key match)
-    fn key_from_name(action: &str) -> Option<u16> {
-        match action.trim() {
-            // Navigation
-            "select" | "enter" => Some(KEY_ENTER),
-            "exit" | "esc" => Some(KEY_ESC),
-            "up" => Some(KEY_UP),
-            "down" => Some(KEY_DOWN),
-            "left" => Some(KEY_LEFT),
-            "right" => Some(KEY_RIGHT),
-            "home" => Some(KEY_HOME),
-            "pageup" => Some(KEY_PAGEUP),
-            "pagedown" => Some(KEY_PAGEDOWN),
-            "end" => Some(KEY_END),
-            "tab" => Some(KEY_TAB),
-            "backspace" => Some(KEY_BACKSPACE),
-            "delete" => Some(KEY_DELETE),
-            "insert" => Some(KEY_INSERT),
+    fn release_sticky(&mut self, code: u16) -> Result<()> {
+        if let Some(idx) = self.sticky.iter().position(|k| *k == code) {
+            self.sticky.remove(idx);
+            self.key_up(code)?;
+        }
+        Ok(())
+    }
 
-            // Function keys
-            "f1" => Some(KEY_F1),
-            "f2" => Some(KEY_F2),
-            "f3" => Some(KEY_F3),
-            "f4" => Some(KEY_F4),
-            "f5" => Some(KEY_F5),
-            "f6" => Some(KEY_F6),
-            "f7" => Some(KEY_F7),
-            "f8" => Some(KEY_F8),
-            "f9" => Some(KEY_F9),
-            "f10" => Some(KEY_F10),
-            "f11" => Some(KEY_F11),
-            "f12" => Some(KEY_F12),
+    fn click(&mut self, code: u16) -> Result<()> {
+        self.key_down(code)?;
+        self.key_up(code)
+    }
 
-            // Numbers
-            "0" => Some(KEY_10),
-            "1" => Some(KEY_1),
-            "2" => Some(KEY_2),
-            "3" => Some(KEY_3),
-            "4" => Some(KEY_4),
-            "5" => Some(KEY_5),
-            "6" => Some(KEY_6),
-            "7" => Some(KEY_7),
-            "8" => Some(KEY_8),
-            "9" => Some(KEY_9),
+    fn key_down(&mut self, code: u16) -> Result<()> {
+        if let Some((_, count)) = self.held.iter_mut().find(|(k, _)| *k == code) {
+            *count += 1;
+            return Ok(());
+        }
+        self.held.push((code, 1));
+        self.device.press(code).map_err(|e| anyhow::anyhow!("{}", e))
+    }
 
-            // Special keys
-            "space" => Some(KEY_SPACE),
-            "spacebar" => Some(KEY_SPACE),
-            "dot" => Some(KEY_DOT),
-            "comma" => Some(KEY_COMMA),
-            "minus" => Some(KEY_MINUS),
-            "equal" => Some(KEY_EQUAL),
-            "slash" => Some(KEY_SLASH),
-            "backslash" => Some(KEY_BACKSLASH),
-            "semicolon" => Some(KEY_SEMICOLON),
-            "apostrophe" => Some(KEY_APOSTROPHE),
-            "leftbrace" => Some(KEY_LEFTBRACE),
-            "rightbrace" => Some(KEY_RIGHTBRACE),
-            "grave" => Some(KEY_GRAVE),
+    fn key_up(&mut self, code: u16) -> Result<()> {
+        let Some(idx) = self.held.iter().position(|(k, _)| *k == code) else {
+            return Ok(());
+        };
+        self.held[idx].1 -= 1;
+        if self.held[idx].1 > 0 {
+            return Ok(());
+        }
+        self.held.remove(idx);
+        self.device.release(code).map_err(|e| anyhow::anyhow!("{}", e))
+    }
 
-            // Letters
-            "a" => Some(KEY_A),
-            "b" => Some(KEY_B),
-            "c" => Some(KEY_C),
-            "d" => Some(KEY_D),
-            "e" => Some(KEY_E),
-            "f" => Some(KEY_F),
-            "g" => Some(KEY_G),
-            "h" => Some(KEY_H),
-            "i" => Some(KEY_I),
-            "j" => Some(KEY_J),
-            "k" => Some(KEY_K),
-            "l" => Some(KEY_L),
-            "m" => Some(KEY_M),
-            "n" => Some(KEY_N),
-            "o" => Some(KEY_O),
-            "p" => Some(KEY_P),
-            "q" => Some(KEY_Q),
-            "r" => Some(KEY_R),
-            "s" => Some(KEY_S),
-            "t" => Some(KEY_T),
-            "u" => Some(KEY_U),
-            "v" => Some(KEY_V),
-            "w" => Some(KEY_W),
-            "x" => Some(KEY_X),
-            "y" => Some(KEY_Y),
-            "z" => Some(KEY_Z),
+    // Move the pointer by a relative amount, positive y moving up
+    pub fn move_mouse(&mut self, x: i32, y: i32) -> Result<()> {
+        self.device.move_mouse(x, y).map_err(|e| anyhow::anyhow!("{}", e))
+    }
 
-            // Arrow keys (alternative names)
-            "arrow_up" => Some(KEY_UP),
-            "arrow_down" => Some(KEY_DOWN),
-            "arrow_left" => Some(KEY_LEFT),
-            "arrow_right" => Some(KEY_RIGHT),
+    // Move the pointer to a position given as fractions of the screen
+    pub fn pointer_to(&mut self, x: f64, y: f64) -> Result<()> {
+        let Some(absolute) = self.absolute.as_mut() else {
+            warn!("No absolute pointer device to move the pointer to {}, {}", x, y);
+            return Ok(());
+        };
+        absolute.move_to(x, y).map_err(|e| anyhow::anyhow!("{}", e))
+    }
 
-            // Common aliases
-            "del" => Some(KEY_DELETE),
-            "ins" => Some(KEY_INSERT),
-            "pgup" => Some(KEY_PAGEUP),
-            "pgdown" => Some(KEY_PAGEDOWN),
-            "return" => Some(KEY_ENTER),
+    // Scroll by high-resolution wheel units, positive y scrolling up
+    pub fn scroll(&mut self, x: i32, y: i32) -> Result<()> {
+        self.device.scroll(x, y).map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
 
-            _ => None,
-        }
+impl Drop for UInputDevice {
+    // Never leave keys stuck down when the bridge exits
+    fn drop(&mut self) {
+        let _ = self.release_all();
     }
 }
@@ -1,15 +1,38 @@
+mod action;
+mod config;
+mod dispatch;
+mod exec;
+mod executor;
+mod grid;
+mod http;
+mod keys;
+mod kodi;
+mod layout;
 mod linux;
+mod mpris;
+mod mpv;
+mod mqtt;
+mod pointer;
+mod ratelimit;
+mod sequence;
+mod t9;
+mod uinput;
 
 use anyhow::Result;
-use serde::Deserialize;
 use serde_yaml_ng;
-use std::collections::HashMap;
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
 use clap::Parser;
 use cec_rs::{CecConnectionCfgBuilder, CecDeviceType, CecDeviceTypeVec, CecKeypress, CecUserControlCode};
 use std::ffi::CString;
 use log::{debug, info, warn, error};
+use action::Action;
+use config::Config;
+use dispatch::Dispatcher;
+use executor::Executor;
+use mqtt::Mqtt;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -84,30 +107,6 @@ struct Args {
     quiet: bool,
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    device_name: String,
-    #[serde(default = "default_physical_address")]
-    physical_address: u16,
-    #[serde(default = "default_cec_version")]
-    cec_version: String,
-    #[serde(default = "default_log_level")]
-    log_level: String,
-    mappings: HashMap<String, String>,
-}
-
-fn default_physical_address() -> u16 {
-    0x1000  // Default to HDMI port 1
-}
-
-fn default_cec_version() -> String {
-    "1.4".to_string()
-}
-
-fn default_log_level() -> String {
-    "info".to_string()
-}
-
 fn init_logging(level: &str, quiet: bool) -> Result<()> {
     if quiet {
         // In quiet mode, suppress all output including logs
@@ -140,6 +139,142 @@ fn init_logging(level: &str, quiet: bool) -> Result<()> {
     Ok(())
 }
 
+// Input handled by the main loop
+enum Event {
+    Keypress(CecKeypress),
+    // Action received on the MQTT command topic
+    Command(Action),
+}
+
+// Defines cec_event_name() and BUTTON_NAMES from one list, so the button names used as keys
+// in `mappings` are the CecUserControlCode variant names and can be checked at load time
+macro_rules! cec_buttons {
+    ($($code:ident),* $(,)?) => {
+        // Map a CEC user control code to the button name used as key in `mappings`
+        fn cec_event_name(key_code: CecUserControlCode) -> Option<&'static str> {
+            match key_code {
+                $(CecUserControlCode::$code => Some(stringify!($code)),)*
+                // Unknown or unhandled
+                CecUserControlCode::Unknown => None,
+            }
+        }
+
+        // Every button name cec_event_name() returns
+        const BUTTON_NAMES: &[&str] = &[$(stringify!($code)),*];
+    };
+}
+
+cec_buttons! {
+    // Navigation Controls
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Enter,
+    Exit,
+    RightUp,
+    RightDown,
+    LeftUp,
+    LeftDown,
+
+    // Menu Controls
+    RootMenu,
+    SetupMenu,
+    ContentsMenu,
+    FavoriteMenu,
+    TopMenu,
+    DvdMenu,
+
+    // Media Controls
+    Play,
+    Pause,
+    Stop,
+    Record,
+    Rewind,
+    FastForward,
+    Eject,
+    Forward,
+    Backward,
+    StopRecord,
+    PauseRecord,
+
+    // Audio Controls
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    SoundSelect,
+
+    // Power Controls
+    Power,
+    PowerOnFunction,
+    PowerOffFunction,
+    PowerToggleFunction,
+
+    // Channel Controls
+    ChannelUp,
+    ChannelDown,
+    PreviousChannel,
+    NextFavorite,
+
+    // Numeric Controls
+    Number0,
+    Number1,
+    Number2,
+    Number3,
+    Number4,
+    Number5,
+    Number6,
+    Number7,
+    Number8,
+    Number9,
+    Number11,
+    Number12,
+    NumberEntryMode,
+    Dot,
+    Clear,
+
+    // Function Keys
+    F1Blue,
+    F2Red,
+    F3Green,
+    F4Yellow,
+    F5,
+
+    // Information and Help
+    DisplayInformation,
+    Help,
+    PageUp,
+    PageDown,
+    InputSelect,
+
+    // Advanced Media Functions
+    PlayFunction,
+    PausePlayFunction,
+    RecordFunction,
+    PauseRecordFunction,
+    StopFunction,
+    MuteFunction,
+    RestoreVolumeFunction,
+    TuneFunction,
+    SelectMediaFunction,
+    SelectAvInputFunction,
+    SelectAudioInputFunction,
+
+    // Other Controls
+    Angle,
+    SubPicture,
+    VideoOnDemand,
+    ElectronicProgramGuide,
+    TimerProgramming,
+    InitialConfiguration,
+    SelectBroadcastType,
+    SelectSoundPresentation,
+    Data,
+    AnReturn,
+    AnChannelsList,
+}
+
 #[cfg(target_os = "linux")]
 fn main() -> Result<()> {
     let args = Args::parse();
@@ -150,7 +285,7 @@ fn main() -> Result<()> {
     });
 
     let file = File::open(&config_path)?;
-    let config: Config = serde_yaml_ng::from_reader(file)?;
+    let mut config: Config = serde_yaml_ng::from_reader(file)?;
 
     // Determine log level: command line takes precedence, then config, then default
     let log_level = args.log_level.as_deref().unwrap_or(&config.log_level);
@@ -160,10 +295,13 @@ fn main() -> Result<()> {
         info!("No config file specified, using default: {}", config_path.display());
     }
 
+    // Parsed after logging is set up so skipped mappings are reported
+    config.parse_mappings()?;
+
     info!("Initializing CEC with device name: {}", config.device_name);
 
-    // Create a channel for handling keypress events
-    let (tx, rx) = std::sync::mpsc::channel::<CecKeypress>();
+    // Create a channel for handling keypress events and MQTT commands
+    let (tx, rx) = std::sync::mpsc::channel::<Event>();
 
     // Configure CEC connection with enhanced Raspberry Pi CM5 compatibility
     debug!("Configuring CEC with physical address: 0x{:04x}, version: {}",
@@ -179,7 +317,7 @@ fn main() -> Result<()> {
         let key_press_callback = {
             let tx = tx.clone();
             Box::new(move |keypress: CecKeypress| {
-                if let Err(e) = tx.send(keypress) {
+                if let Err(e) = tx.send(Event::Keypress(keypress)) {
                     error!("Failed to send keypress: {}", e);
                 }
             })
@@ -240,144 +378,63 @@ fn main() -> Result<()> {
     };
 
 
-    let mut device = {
+    let device = {
         #[cfg(target_os = "linux")]
         { linux::UInputDevice::new(&config)? }
     };
+    let mqtt = match config.mqtt.clone() {
+        Some(mqtt_config) => Some(Mqtt::new(mqtt_config, move |action| {
+            if let Err(e) = tx.send(Event::Command(action)) {
+                error!("Failed to send MQTT command: {}", e);
+            }
+        })?),
+        None => None,
+    };
+
+    // Input events are sent from a separate thread so timed sequences do not hold up CEC events
+    let executor = Executor::new(device, &config)?;
+    let mut dispatcher = Dispatcher::new(config, executor)?;
 
     info!("CEC2UInput bridge started. Listening for CEC events...");
 
     loop {
-        // Wait for keypress events from the callback
-        if let Ok(keypress) = rx.recv() {
-            // Only process initial keypress, not key repeats
-            if keypress.duration.as_millis() == 0 {
-                let key_code = keypress.keycode;
-                let cec_event = match key_code {
-                    // Navigation Controls
-                    CecUserControlCode::Up => "Up",
-                    CecUserControlCode::Down => "Down",
-                    CecUserControlCode::Left => "Left",
-                    CecUserControlCode::Right => "Right",
-                    CecUserControlCode::Select => "Select",
-                    CecUserControlCode::Enter => "Enter",
-                    CecUserControlCode::Exit => "Exit",
-                    CecUserControlCode::RightUp => "RightUp",
-                    CecUserControlCode::RightDown => "RightDown",
-                    CecUserControlCode::LeftUp => "LeftUp",
-                    CecUserControlCode::LeftDown => "LeftDown",
-
-                    // Menu Controls
-                    CecUserControlCode::RootMenu => "RootMenu",
-                    CecUserControlCode::SetupMenu => "SetupMenu",
-                    CecUserControlCode::ContentsMenu => "ContentsMenu",
-                    CecUserControlCode::FavoriteMenu => "FavoriteMenu",
-                    CecUserControlCode::TopMenu => "TopMenu",
-                    CecUserControlCode::DvdMenu => "DvdMenu",
-
-                    // Media Controls
-                    CecUserControlCode::Play => "Play",
-                    CecUserControlCode::Pause => "Pause",
-                    CecUserControlCode::Stop => "Stop",
-                    CecUserControlCode::Record => "Record",
-                    CecUserControlCode::Rewind => "Rewind",
-                    CecUserControlCode::FastForward => "FastForward",
-                    CecUserControlCode::Eject => "Eject",
-                    CecUserControlCode::Forward => "Forward",
-                    CecUserControlCode::Backward => "Backward",
-                    CecUserControlCode::StopRecord => "StopRecord",
-                    CecUserControlCode::PauseRecord => "PauseRecord",
-
-                    // Audio Controls
-                    CecUserControlCode::VolumeUp => "VolumeUp",
-                    CecUserControlCode::VolumeDown => "VolumeDown",
-                    CecUserControlCode::Mute => "Mute",
-                    CecUserControlCode::SoundSelect => "SoundSelect",
-
-                    // Power Controls
-                    CecUserControlCode::Power => "Power",
-                    CecUserControlCode::PowerOnFunction => "PowerOnFunction",
-                    CecUserControlCode::PowerOffFunction => "PowerOffFunction",
-                    CecUserControlCode::PowerToggleFunction => "PowerToggleFunction",
-
-                    // Channel Controls
-                    CecUserControlCode::ChannelUp => "ChannelUp",
-                    CecUserControlCode::ChannelDown => "ChannelDown",
-                    CecUserControlCode::PreviousChannel => "PreviousChannel",
-                    CecUserControlCode::NextFavorite => "NextFavorite",
-
-                    // Numeric Controls
-                    CecUserControlCode::Number0 => "Number0",
-                    CecUserControlCode::Number1 => "Number1",
-                    CecUserControlCode::Number2 => "Number2",
-                    CecUserControlCode::Number3 => "Number3",
-                    CecUserControlCode::Number4 => "Number4",
-                    CecUserControlCode::Number5 => "Number5",
-                    CecUserControlCode::Number6 => "Number6",
-                    CecUserControlCode::Number7 => "Number7",
-                    CecUserControlCode::Number8 => "Number8",
-                    CecUserControlCode::Number9 => "Number9",
-                    CecUserControlCode::Number11 => "Number11",
-                    CecUserControlCode::Number12 => "Number12",
-                    CecUserControlCode::NumberEntryMode => "NumberEntryMode",
-                    CecUserControlCode::Dot => "Dot",
-                    CecUserControlCode::Clear => "Clear",
-
-                    // Function Keys
-                    CecUserControlCode::F1Blue => "F1Blue",
-                    CecUserControlCode::F2Red => "F2Red",
-                    CecUserControlCode::F3Green => "F3Green",
-                    CecUserControlCode::F4Yellow => "F4Yellow",
-                    CecUserControlCode::F5 => "F5",
-
-                    // Information and Help
-                    CecUserControlCode::DisplayInformation => "DisplayInformation",
-                    CecUserControlCode::Help => "Help",
-                    CecUserControlCode::PageUp => "PageUp",
-                    CecUserControlCode::PageDown => "PageDown",
-                    CecUserControlCode::InputSelect => "InputSelect",
-
-                    // Advanced Media Functions
-                    CecUserControlCode::PlayFunction => "PlayFunction",
-                    CecUserControlCode::PausePlayFunction => "PausePlayFunction",
-                    CecUserControlCode::RecordFunction => "RecordFunction",
-                    CecUserControlCode::PauseRecordFunction => "PauseRecordFunction",
-                    CecUserControlCode::StopFunction => "StopFunction",
-                    CecUserControlCode::MuteFunction => "MuteFunction",
-                    CecUserControlCode::RestoreVolumeFunction => "RestoreVolumeFunction",
-                    CecUserControlCode::TuneFunction => "TuneFunction",
-                    CecUserControlCode::SelectMediaFunction => "SelectMediaFunction",
-                    CecUserControlCode::SelectAvInputFunction => "SelectAvInputFunction",
-                    CecUserControlCode::SelectAudioInputFunction => "SelectAudioInputFunction",
-
-                    // Other Controls
-                    CecUserControlCode::Angle => "Angle",
-                    CecUserControlCode::SubPicture => "SubPicture",
-                    CecUserControlCode::VideoOnDemand => "VideoOnDemand",
-                    CecUserControlCode::ElectronicProgramGuide => "ElectronicProgramGuide",
-                    CecUserControlCode::TimerProgramming => "TimerProgramming",
-                    CecUserControlCode::InitialConfiguration => "InitialConfiguration",
-                    CecUserControlCode::SelectBroadcastType => "SelectBroadcastType",
-                    CecUserControlCode::SelectSoundPresentation => "SelectSoundPresentation",
-                    CecUserControlCode::Data => "Data",
-                    CecUserControlCode::AnReturn => "AnReturn",
-                    CecUserControlCode::AnChannelsList => "AnChannelsList",
-
-                    // Unknown or unhandled
-                    CecUserControlCode::Unknown => {
+        // Wait for keypress events from the callback or MQTT commands, waking up early when a timer is due
+        let received = match dispatcher.next_deadline() {
+            Some(deadline) => match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => anyhow::bail!("CEC keypress channel closed"),
+            },
+            None => rx.recv().ok(),
+        };
+
+        match received {
+            Some(Event::Keypress(keypress)) => {
+                let cec_event = match cec_event_name(keypress.keycode) {
+                    Some(name) => name,
+                    None => {
                         warn!("Unknown CEC key code received");
                         continue;
                     }
                 };
 
-                if let Some(keyboard_event) = config.mappings.get(cec_event) {
-                    debug!("Mapping CEC event '{}' to input event '{}'", cec_event, keyboard_event);
-                    device.send_key(keyboard_event)?;
+                // libcec reports the initial keypress with a zero duration and the release with the hold duration
+                let pressed = keypress.duration.as_millis() == 0;
+                if let Some(mqtt) = &mqtt {
+                    let event = if pressed { "press" } else { "release" };
+                    mqtt.publish_button(cec_event, event, keypress.duration, dispatcher.mode());
+                }
+                if pressed {
+                    dispatcher.handle_press(cec_event, Instant::now())?;
                 } else {
-                    warn!("No mapping found for CEC event: {}", cec_event);
+                    dispatcher.handle_release(cec_event, keypress.duration)?;
                 }
             }
+            Some(Event::Command(action)) => dispatcher.handle_command(&action)?,
+            None => {}
         }
+
+        dispatcher.tick(Instant::now())?;
     }
 }
 
@@ -0,0 +1,324 @@
+use crate::action::MprisCommand;
+use crate::config::MprisConfig;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::{connection, proxy, Connection, MessageIterator, Proxy};
+use zbus::message::Type;
+use zbus::names::BusName;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedValue;
+use zbus::MatchRule;
+use log::{debug, warn};
+
+const BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Sends mpris() commands to media players on the session bus from a background thread.
+///
+/// The bus is connected on first use and again after an error, so the bridge starts without a
+/// session bus and picks it up once the desktop or player is running. While connected, a
+/// second thread follows PlaybackStatus changes to know which player started playing last.
+pub struct Mpris {
+    tx: Sender<(MprisCommand, Option<String>)>,
+}
+
+impl Mpris {
+    pub fn new(config: MprisConfig) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<(MprisCommand, Option<String>)>();
+        thread::Builder::new().name("mpris".to_string()).spawn(move || {
+            let mut client = Client { config, connection: None, last_playing: Arc::new(Mutex::new(None)) };
+            for (command, player) in rx {
+                if let Err(e) = client.run(command, player.as_deref()) {
+                    warn!("MPRIS command '{}' failed: {}", command, e);
+                    client.disconnect();
+                }
+            }
+        })?;
+        Ok(Self { tx })
+    }
+
+    /// Queue a command for `player`, or for the configured or most recently playing player
+    pub fn send(&self, command: MprisCommand, player: Option<&str>) -> Result<()> {
+        self.tx.send((command, player.map(str::to_string))).map_err(|_| anyhow::anyhow!("MPRIS thread stopped"))
+    }
+}
+
+struct Client {
+    config: MprisConfig,
+    connection: Option<Connection>,
+    // Unique bus name of the player that most recently started playing, set by the watch thread
+    last_playing: Arc<Mutex<Option<String>>>,
+}
+
+impl Client {
+    fn run(&mut self, command: MprisCommand, player: Option<&str>) -> Result<()> {
+        let connection = self.connect()?;
+        let wanted = player.map(str::to_string).or_else(|| self.config.player.clone());
+        let name = self.find_player(&connection, wanted.as_deref())?;
+        debug!("Sending MPRIS command '{}' to {}", command, name);
+
+        let player = player_proxy(&connection, name)?;
+        match command {
+            MprisCommand::PlayPause => { player.call_method("PlayPause", &())?; }
+            MprisCommand::Play => { player.call_method("Play", &())?; }
+            MprisCommand::Pause => { player.call_method("Pause", &())?; }
+            MprisCommand::Stop => { player.call_method("Stop", &())?; }
+            MprisCommand::Next => { player.call_method("Next", &())?; }
+            MprisCommand::Previous => { player.call_method("Previous", &())?; }
+            MprisCommand::Seek(offset) => { player.call_method("Seek", &(offset,))?; }
+            MprisCommand::Volume { value, relative } => {
+                let current: f64 = if relative { player.get_property("Volume")? } else { 0.0 };
+                player.set_property("Volume", volume(current, value, relative))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<Connection> {
+        if let Some(connection) = &self.connection {
+            return Ok(connection.clone());
+        }
+        let connection = match &self.config.bus {
+            Some(address) => connection::Builder::address(address.as_str())?.build()?,
+            None => Connection::session()?,
+        };
+        watch_playing(&connection, self.last_playing.clone())?;
+        self.connection = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn disconnect(&mut self) {
+        // Closing the connection also ends the thread watching it
+        if let Some(connection) = self.connection.take() {
+            let _ = connection.close();
+        }
+    }
+
+    // Bus name of the player called `wanted`, or of the most recently playing player
+    fn find_player(&mut self, connection: &Connection, wanted: Option<&str>) -> Result<String> {
+        let players: Vec<String> = DBusProxy::new(connection)?
+            .list_names()?
+            .into_iter()
+            .map(|name| name.to_string())
+            .filter(|name| name.starts_with(BUS_PREFIX))
+            .collect();
+
+        if let Some(wanted) = wanted {
+            // Players running several instances append ".instance<pid>" to their name
+            let wanted = wanted.to_lowercase();
+            return players.into_iter()
+                .find(|name| {
+                    let id = name[BUS_PREFIX.len()..].to_lowercase();
+                    id == wanted || id.starts_with(&format!("{}.", wanted))
+                })
+                .ok_or_else(|| anyhow::anyhow!("No MPRIS player named '{}'", wanted));
+        }
+
+        // Prefer the player that started playing last, then another playing one, then the one
+        // last seen playing, then a paused one
+        let dbus = DBusProxy::new(connection)?;
+        let last_playing = self.last_playing.lock().unwrap().clone();
+        let is_last = |name: &str| {
+            last_playing.is_some() && BusName::try_from(name).ok()
+                .and_then(|name| dbus.get_name_owner(name).ok())
+                .is_some_and(|owner| Some(owner.as_str()) == last_playing.as_deref())
+        };
+        let mut playing = Vec::new();
+        let mut paused = None;
+        for name in &players {
+            let status: String = player_proxy(connection, name.clone())
+                .and_then(|proxy| proxy.get_property("PlaybackStatus"))
+                .unwrap_or_default();
+            match status.as_str() {
+                "Playing" => playing.push(name.clone()),
+                "Paused" if paused.is_none() => paused = Some(name.clone()),
+                _ => {}
+            }
+        }
+        if let Some(name) = playing.iter().find(|name| is_last(name)).or(playing.first()) {
+            return Ok(name.clone());
+        }
+        if let Some(name) = players.iter().find(|name| is_last(name)) {
+            return Ok(name.clone());
+        }
+        paused.or_else(|| players.into_iter().next()).ok_or_else(|| anyhow::anyhow!("No MPRIS player running"))
+    }
+}
+
+// Volume after changing `current` by `value`, or setting it when not relative, within 0 to 1
+fn volume(current: f64, value: f64, relative: bool) -> f64 {
+    (if relative { current + value } else { value }).clamp(0.0, 1.0)
+}
+
+// Record the unique bus name of every player that starts playing, from the PropertiesChanged
+// signals of the player interface, until the connection is closed
+fn watch_playing(connection: &Connection, last_playing: Arc<Mutex<Option<String>>>) -> Result<()> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(OBJECT_PATH)?
+        .arg(0, PLAYER_INTERFACE)?
+        .build();
+    let messages = MessageIterator::for_match_rule(rule, connection, Some(16))?;
+    thread::Builder::new().name("mpris-watch".to_string()).spawn(move || {
+        for message in messages {
+            let Ok(message) = message else { break };
+            let Some(sender) = message.header().sender().map(|sender| sender.to_string()) else { continue };
+            let Ok((_, changed, _)) = message.body().deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>() else {
+                continue;
+            };
+            let status = changed.get("PlaybackStatus").and_then(|status| <&str>::try_from(&**status).ok());
+            if status == Some("Playing") {
+                debug!("MPRIS player {} started playing", sender);
+                *last_playing.lock().unwrap() = Some(sender);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+// Player interface of a player, reading properties fresh on every access
+fn player_proxy(connection: &Connection, name: String) -> zbus::Result<Proxy<'static>> {
+    proxy::Builder::new(connection)
+        .destination(name)?
+        .path(OBJECT_PATH)?
+        .interface(PLAYER_INTERFACE)?
+        .cache_properties(CacheProperties::No)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+    use zbus::interface;
+
+    struct FakePlayer {
+        name: &'static str,
+        status: String,
+        volume: f64,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl FakePlayer {
+        fn next(&self) {
+            self.calls.lock().unwrap().push(format!("{} next", self.name));
+        }
+
+        fn seek(&self, offset: i64) {
+            self.calls.lock().unwrap().push(format!("{} seek {}", self.name, offset));
+        }
+
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.clone()
+        }
+
+        #[zbus(property)]
+        fn volume(&self) -> f64 {
+            self.volume
+        }
+
+        #[zbus(property)]
+        fn set_volume(&mut self, volume: f64) {
+            self.calls.lock().unwrap().push(format!("{} volume {}", self.name, volume));
+            self.volume = volume;
+        }
+    }
+
+    // A private session bus, killed when dropped
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn player(address: &str, name: &'static str, calls: &Arc<Mutex<Vec<String>>>) -> Connection {
+        let player = FakePlayer { name, status: "Stopped".to_string(), volume: 0.5, calls: calls.clone() };
+        connection::Builder::address(address).unwrap()
+            .name(format!("{}{}", BUS_PREFIX, name)).unwrap()
+            .serve_at(OBJECT_PATH, player).unwrap()
+            .build().unwrap()
+    }
+
+    // Change a fake player's PlaybackStatus and emit PropertiesChanged like a real player
+    fn set_status(player: &Connection, status: &str) {
+        let iface = player.object_server().interface::<_, FakePlayer>(OBJECT_PATH).unwrap();
+        iface.get_mut().status = status.to_string();
+        zbus::block_on(iface.get().playback_status_changed(iface.signal_emitter())).unwrap();
+    }
+
+    // Wait for the watch thread to see `player` start playing
+    fn wait_for_playing(client: &Client, player: &Connection) {
+        let name = player.unique_name().unwrap().to_string();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.last_playing.lock().unwrap().as_deref() != Some(name.as_str()) {
+            assert!(Instant::now() < deadline, "PlaybackStatus change of {} not seen", name);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn volume_changes() {
+        assert_eq!(volume(0.5, 0.05, true), 0.55);
+        assert_eq!(volume(0.98, 0.05, true), 1.0);
+        assert_eq!(volume(0.02, -0.05, true), 0.0);
+        assert_eq!(volume(0.9, 0.3, false), 0.3);
+    }
+
+    #[test]
+    fn commands_follow_the_last_playing_player() {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--nopidfile", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let Ok(mut daemon) = daemon else {
+            eprintln!("dbus-daemon not found, skipping");
+            return;
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let _bus = Bus(daemon);
+        let address = address.trim();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let a = player(address, "a", &calls);
+        let b = player(address, "b.instance42", &calls);
+        let config = MprisConfig { player: None, bus: Some(address.to_string()) };
+        let mut client = Client { config, connection: None, last_playing: Arc::new(Mutex::new(None)) };
+        let _ = client.connect().unwrap();
+
+        set_status(&b, "Playing");
+        wait_for_playing(&client, &b);
+        set_status(&a, "Playing");
+        wait_for_playing(&client, &a);
+        client.run(MprisCommand::Next, None).unwrap();
+
+        // Once neither plays, commands still go to the one that played last
+        set_status(&b, "Playing");
+        wait_for_playing(&client, &b);
+        set_status(&a, "Paused");
+        set_status(&b, "Paused");
+        client.run(MprisCommand::Seek(-10_000_000), None).unwrap();
+
+        client.run(MprisCommand::Volume { value: 0.05, relative: true }, Some("a")).unwrap();
+        client.run(MprisCommand::Volume { value: 0.2, relative: false }, Some("B")).unwrap();
+        let error = client.run(MprisCommand::Next, Some("vlc")).unwrap_err();
+        assert_eq!(error.to_string(), "No MPRIS player named 'vlc'");
+
+        assert_eq!(*calls.lock().unwrap(), ["a next", "b.instance42 seek -10000000", "a volume 0.55", "b.instance42 volume 0.2"]);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::config::MpvConfig;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+
+// How long to wait for mpv to answer a command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sends mpv() commands to mpv's JSON IPC socket (`--input-ipc-server`) from a background thread.
+///
+/// The socket is connected on first use. When mpv restarts, writing to the old connection fails
+/// and the command is sent on a new one.
+pub struct Mpv {
+    tx: Sender<Vec<String>>,
+}
+
+impl Mpv {
+    pub fn new(config: MpvConfig) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Vec<String>>();
+        thread::Builder::new().name("mpv".to_string()).spawn(move || {
+            let mut client = Client { config, stream: None, request_id: 0 };
+            for command in rx {
+                if let Err(e) = client.send(&command) {
+                    warn!("mpv command '{}' failed: {}", command.join(" "), e);
+                }
+            }
+        })?;
+        Ok(Self { tx })
+    }
+
+    pub fn send(&self, command: &[String]) -> Result<()> {
+        self.tx.send(command.to_vec()).map_err(|_| anyhow::anyhow!("mpv thread stopped"))
+    }
+}
+
+struct Client {
+    config: MpvConfig,
+    stream: Option<BufReader<UnixStream>>,
+    request_id: u64,
+}
+
+impl Client {
+    fn send(&mut self, command: &[String]) -> Result<()> {
+        self.request_id += 1;
+        let mut line = json!({ "command": command, "request_id": self.request_id }).to_string();
+        line.push('\n');
+
+        // A connection left over from an mpv that has since exited only fails on use
+        let sent = self.stream.as_mut().is_some_and(|stream| stream.get_mut().write_all(line.as_bytes()).is_ok());
+        if !sent {
+            let stream = UnixStream::connect(&self.config.socket)
+                .map_err(|e| anyhow::anyhow!("Cannot connect to {}: {}", self.config.socket, e))?;
+            stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+            debug!("Connected to mpv at {}", self.config.socket);
+            let stream = self.stream.insert(BufReader::new(stream));
+            stream.get_mut().write_all(line.as_bytes())?;
+        }
+
+        let reply = self.reply();
+        if let Err(Error::Connection(_)) = &reply {
+            self.stream = None;
+        }
+        reply.map_err(|(Error::Connection(e) | Error::Command(e))| anyhow::anyhow!("{}", e))
+    }
+
+    // Wait for the reply to the last request; mpv also writes events to the socket, which are skipped
+    fn reply(&mut self) -> Result<(), Error> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(Error::Connection("not connected".to_string()));
+        };
+        let mut reply = String::new();
+        loop {
+            reply.clear();
+            match stream.read_line(&mut reply) {
+                Ok(0) => return Err(Error::Connection("mpv closed the connection".to_string())),
+                Ok(_) => {}
+                Err(e) => return Err(Error::Connection(e.to_string())),
+            }
+            let Ok(message) = serde_json::from_str::<Value>(&reply) else {
+                continue;
+            };
+            if message["request_id"] != self.request_id {
+                continue;
+            }
+            return match message["error"].as_str() {
+                Some("success") => Ok(()),
+                Some(error) => Err(Error::Command(error.to_string())),
+                None => Err(Error::Command(format!("unexpected reply {}", reply.trim()))),
+            };
+        }
+    }
+}
+
+enum Error {
+    // The connection is unusable and has to be opened again
+    Connection(String),
+    // mpv rejected the command
+    Command(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    // Answer the requests on one connection with the given errors, each after an unrelated
+    // event, then close it
+    fn serve(listener: &UnixListener, answers: &[&str]) -> Vec<Value> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut requests = Vec::new();
+        for answer in answers {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            let reply = json!({ "request_id": request["request_id"], "error": answer, "data": null });
+            writeln!(reader.get_mut(), "{}\n{}", json!({ "event": "pause" }), reply).unwrap();
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[test]
+    fn commands_and_reconnect() {
+        let socket = std::env::temp_dir().join(format!("cec2uinput-test-mpv-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        // The first mpv exits after two commands, the client has to connect to the next one
+        let server = thread::spawn(move || {
+            let mut requests = serve(&listener, &["success", "invalid parameter"]);
+            requests.extend(serve(&listener, &["success"]));
+            requests
+        });
+
+        let words = |text: &str| text.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let mut client = Client { config: MpvConfig { socket: socket.display().to_string() }, stream: None, request_id: 0 };
+        client.send(&words("cycle pause")).unwrap();
+        let error = client.send(&words("seek x")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid parameter");
+        // Give the old connection time to close
+        thread::sleep(Duration::from_millis(50));
+        client.send(&words("seek 10")).unwrap();
+
+        let requests = server.join().unwrap();
+        let _ = std::fs::remove_file(&socket);
+        assert_eq!(requests, [
+            json!({ "command": ["cycle", "pause"], "request_id": 1 }),
+            json!({ "command": ["seek", "x"], "request_id": 2 }),
+            json!({ "command": ["seek", "10"], "request_id": 3 }),
+        ]);
+    }
+}
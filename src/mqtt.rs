@@ -0,0 +1,148 @@
+use crate::action::Action;
+use crate::config::MqttConfig;
+use anyhow::Result;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use log::{debug, info, warn};
+
+// Pause before connecting again after the broker connection failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Messages waiting to be sent to the broker
+const QUEUE_SIZE: usize = 64;
+// Longest a command may keep the input thread busy in sleeps, holds and delays, and the most
+// actions it may send, so a message cannot lock up the remote
+const MAX_COMMAND_WAIT: Duration = Duration::from_secs(10);
+const MAX_COMMAND_STEPS: u64 = 1000;
+
+/// MQTT bridge publishing CEC button events and receiving actions on the command topic.
+///
+/// A background thread keeps the broker connection, reconnecting after errors, and hands
+/// parsed command actions to `on_command`. Publishing never blocks: while the broker is
+/// unreachable events are queued, and dropped once the queue is full.
+pub struct Mqtt {
+    client: Client,
+    topic: String,
+}
+
+impl Mqtt {
+    pub fn new(config: MqttConfig, on_command: impl Fn(Action) + Send + 'static) -> Result<Self> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, QUEUE_SIZE);
+        let topic = config.topic.clone();
+
+        let subscriber = client.clone();
+        thread::Builder::new().name("mqtt".to_string()).spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                        // Subscriptions do not outlive the connection with a clean session
+                        if let Some(topic) = &config.command_topic {
+                            if let Err(e) = subscriber.try_subscribe(topic, QoS::AtMostOnce) {
+                                warn!("Failed to subscribe to MQTT topic {}: {}", topic, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(message))) => {
+                        let text = String::from_utf8_lossy(&message.payload);
+                        match command(&text, config.allow_exec) {
+                            Ok(action) => {
+                                debug!("Running MQTT command '{}'", action);
+                                on_command(action);
+                            }
+                            Err(e) => warn!("Ignoring MQTT command '{}': {}", text, e),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection to {}:{} failed: {}", config.host, config.port, e);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        })?;
+        Ok(Self { client, topic })
+    }
+
+    /// Publish a button press or release, `mode` being the active mapping layer
+    pub fn publish_button(&self, button: &str, event: &str, duration: Duration, mode: &str) {
+        let payload = json!({
+            "button": button,
+            "event": event,
+            "duration_ms": duration.as_millis() as u64,
+            "mode": mode,
+        });
+        if let Err(e) = self.client.try_publish(&self.topic, QoS::AtMostOnce, false, payload.to_string()) {
+            debug!("Dropping MQTT button event: {}", e);
+        }
+    }
+}
+
+// A command message, parsed like a mapping action
+fn command(text: &str, allow_exec: bool) -> Result<Action> {
+    let action = Action::parse(text.trim())?;
+    let mut exec = false;
+    action.walk(&mut |a| exec |= matches!(a, Action::Exec(_)));
+    if exec && !allow_exec {
+        anyhow::bail!("exec() and spawn() are not allowed over MQTT unless allow_exec is set");
+    }
+    let (wait, steps) = cost(&action);
+    if wait > MAX_COMMAND_WAIT {
+        anyhow::bail!("commands may wait at most {}s, this one waits {} ms", MAX_COMMAND_WAIT.as_secs(), wait.as_millis());
+    }
+    if steps > MAX_COMMAND_STEPS {
+        anyhow::bail!("commands may send at most {} actions, this one sends {}", MAX_COMMAND_STEPS, steps);
+    }
+    Ok(action)
+}
+
+// Time the input thread spends in the sleeps, holds and delays of `action`, and the number of
+// actions and characters it sends
+fn cost(action: &Action) -> (Duration, u64) {
+    match action {
+        Action::Sequence(items) => items.iter().map(cost).fold((Duration::ZERO, 0), |(wait, steps), (w, s)| {
+            (wait.saturating_add(w), steps.saturating_add(s))
+        }),
+        Action::WithKeys(_, inner) => cost(inner),
+        Action::Repeat(inner, count) => {
+            let (wait, steps) = cost(inner);
+            (wait.saturating_mul(*count), steps.saturating_mul(*count as u64))
+        }
+        Action::Delay(delay, inner) => {
+            // The delay comes between the keys, so at most once per action
+            let (wait, steps) = cost(inner);
+            (wait.saturating_add(delay.saturating_mul(steps.min(u32::MAX as u64) as u32)), steps)
+        }
+        Action::Sleep(duration) | Action::Hold(_, duration) => (*duration, 1),
+        Action::Text(text) => (Duration::ZERO, text.chars().count() as u64),
+        _ => (Duration::ZERO, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_needs_allow_exec() {
+        assert!(command("exec(\"true\")", false).is_err());
+        assert!(command("a, spawn(\"true\")*2", false).is_err());
+        assert!(command("exec(\"true\")", true).is_ok());
+    }
+
+    #[test]
+    fn long_commands_are_refused() {
+        assert!(command("a, sleep(5s), b", false).is_ok());
+        assert!(command("sleep(60s)", false).is_err());
+        assert!(command("hold(enter, 6s)*2", false).is_err());
+        assert!(command("delay(20ms, a*600)", false).is_err());
+        assert!(command("a*4000000000", false).is_err());
+        assert!(command("sleep(60s)*4000000000", false).is_err());
+    }
+}
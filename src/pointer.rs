@@ -0,0 +1,107 @@
+use crate::config::{Curve, MouseConfig, ScrollConfig};
+use crate::uinput::WHEEL_NOTCH;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Continuous pointer motion while mouse direction buttons are held.
+///
+/// Speed ramps from `base_speed` to `max_speed` over `accel_time_ms` along the configured
+/// curve, and motion is emitted every `tick_ms`. Holding two directions moves diagonally.
+pub struct Pointer {
+    config: MouseConfig,
+    // Held direction buttons with their unit vector and press time
+    active: HashMap<&'static str, Motion>,
+    next_tick: Option<Instant>,
+    // Sub-pixel motion carried over between ticks
+    remainder: (f64, f64),
+}
+
+struct Motion {
+    direction: (f64, f64),
+    started: Instant,
+}
+
+impl Pointer {
+    pub fn new(config: MouseConfig) -> Self {
+        Self { config, active: HashMap::new(), next_tick: None, remainder: (0.0, 0.0) }
+    }
+
+    /// Continuous scrolling while scroll buttons are held, emitting motion in high-resolution
+    /// wheel units (1/120 of a notch)
+    pub fn scroll(config: &ScrollConfig) -> Self {
+        let units = f64::from(WHEEL_NOTCH);
+        Self::new(MouseConfig {
+            base_speed: config.base_speed * units,
+            max_speed: config.max_speed * units,
+            accel_time_ms: config.accel_time_ms,
+            curve: config.curve,
+            tick_ms: config.tick_ms,
+            step: config.step * WHEEL_NOTCH,
+        })
+    }
+
+    /// Start moving in `direction` until `stop` is called for the same button
+    pub fn start(&mut self, button: &'static str, direction: (f64, f64), now: Instant) {
+        if self.active.is_empty() {
+            self.remainder = (0.0, 0.0);
+            self.next_tick = Some(now);
+        }
+        self.active.insert(button, Motion { direction, started: now });
+    }
+
+    /// Stop the motion started by `button`, returns false if it was not moving the pointer
+    pub fn stop(&mut self, button: &'static str) -> bool {
+        let stopped = self.active.remove(button).is_some();
+        if self.active.is_empty() {
+            self.next_tick = None;
+        }
+        stopped
+    }
+
+    /// Stop all motion, returns false if nothing was moving
+    pub fn stop_all(&mut self) -> bool {
+        let stopped = !self.active.is_empty();
+        self.active.clear();
+        self.next_tick = None;
+        stopped
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_tick
+    }
+
+    /// Whole-pixel motion to emit at `now`, if a tick is due
+    pub fn tick(&mut self, now: Instant) -> Option<(i32, i32)> {
+        let due = self.next_tick.filter(|t| *t <= now)?;
+        let tick = Duration::from_millis(self.config.tick_ms.max(1));
+        self.next_tick = Some(if due + tick <= now { now + tick } else { due + tick });
+
+        let secs = tick.as_secs_f64();
+        let (mut dx, mut dy) = self.remainder;
+        for motion in self.active.values() {
+            let speed = self.speed(now.saturating_duration_since(motion.started));
+            dx += motion.direction.0 * speed * secs;
+            dy += motion.direction.1 * speed * secs;
+        }
+
+        let (x, y) = (dx.trunc(), dy.trunc());
+        self.remainder = (dx - x, dy - y);
+        if x == 0.0 && y == 0.0 {
+            return None;
+        }
+        Some((x as i32, y as i32))
+    }
+
+    // Pointer speed in pixels per second after a direction has been held for `held`
+    fn speed(&self, held: Duration) -> f64 {
+        let accel_time = self.config.accel_time_ms.max(1) as f64;
+        let t = (held.as_millis() as f64 / accel_time).min(1.0);
+        let shape = match self.config.curve {
+            Curve::Linear => t,
+            Curve::Quadratic => t * t,
+            Curve::Cubic => t * t * t,
+            Curve::Smooth => t * t * (3.0 - 2.0 * t),
+        };
+        self.config.base_speed + (self.config.max_speed - self.config.base_speed).max(0.0) * shape
+    }
+}
@@ -0,0 +1,82 @@
+use crate::config::FloodLimit;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use log::debug;
+
+/// Drops duplicate and flooding button presses before they reach the mappings.
+///
+/// Some TVs and adapters deliver the same initial keypress twice within a few milliseconds;
+/// a per-button minimum interval filters those out, and an optional global limit caps the
+/// number of presses accepted within a time window.
+pub struct RateLimiter {
+    flood_limit: Option<FloodLimit>,
+    last_press: HashMap<&'static str, Instant>,
+    // Times of the accepted presses inside the flood limit window
+    recent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(flood_limit: Option<FloodLimit>) -> Self {
+        Self { flood_limit, last_press: HashMap::new(), recent: VecDeque::new() }
+    }
+
+    /// Returns false if the press must be dropped
+    pub fn allow(&mut self, button: &'static str, min_interval: Duration, now: Instant) -> bool {
+        if let Some(last) = self.last_press.get(button) {
+            let elapsed = now.saturating_duration_since(*last);
+            if elapsed < min_interval {
+                debug!("Dropping CEC event '{}' {} ms after the previous press (minimum interval {} ms)",
+                       button, elapsed.as_millis(), min_interval.as_millis());
+                return false;
+            }
+        }
+
+        if let Some(limit) = &self.flood_limit {
+            let window = Duration::from_millis(limit.window_ms);
+            while self.recent.front().is_some_and(|t| now.saturating_duration_since(*t) >= window) {
+                self.recent.pop_front();
+            }
+            if self.recent.len() >= limit.max_presses {
+                debug!("Dropping CEC event '{}', more than {} presses within {} ms",
+                       button, limit.max_presses, limit.window_ms);
+                return false;
+            }
+            self.recent.push_back(now);
+        }
+
+        self.last_press.insert(button, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_presses() {
+        let mut limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        let interval = Duration::from_millis(100);
+        assert!(limiter.allow("Up", interval, now));
+        assert!(!limiter.allow("Up", interval, now + Duration::from_millis(20)));
+        assert!(limiter.allow("Down", interval, now + Duration::from_millis(20)));
+        // Dropped presses do not restart the interval
+        assert!(limiter.allow("Up", interval, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn flood_limit() {
+        let mut limiter = RateLimiter::new(Some(FloodLimit { max_presses: 3, window_ms: 1000 }));
+        let now = Instant::now();
+        let at = |ms| now + Duration::from_millis(ms);
+        assert!(limiter.allow("Number1", Duration::ZERO, at(0)));
+        assert!(limiter.allow("Number2", Duration::ZERO, at(100)));
+        assert!(limiter.allow("Number3", Duration::ZERO, at(200)));
+        assert!(!limiter.allow("Number4", Duration::ZERO, at(300)));
+        // The window slides: the first press leaves it after a second
+        assert!(!limiter.allow("Number4", Duration::ZERO, at(999)));
+        assert!(limiter.allow("Number4", Duration::ZERO, at(1000)));
+        assert!(!limiter.allow("Number5", Duration::ZERO, at(1050)));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::action::Action;
+use crate::config::{Config, SequencePolicy};
+use std::time::{Duration, Instant};
+use log::debug;
+
+/// Matches ordered multi-button sequences such as `"Number1 Number2 Number3"`.
+///
+/// Presses that may start or continue a sequence are buffered until the sequence completes,
+/// becomes impossible or times out. Buffered presses that do not end up in a sequence are
+/// handed back so they fall through to the single-button mappings.
+pub struct SequenceMatcher {
+    sequences: Vec<(Vec<String>, Action)>,
+    timeout: Duration,
+    policy: SequencePolicy,
+    buffer: Vec<Buffered>,
+    deadline: Option<Instant>,
+}
+
+/// Result of feeding a button event to the matcher
+#[derive(Debug)]
+pub enum SequenceOutput {
+    /// A sequence matched, send its action
+    Action(Action),
+    /// Handle this press as a single-button press
+    Press(&'static str),
+    /// Handle this release as a single-button release
+    Release(&'static str, Duration),
+}
+
+struct Buffered {
+    button: &'static str,
+    // Set once the release frame for this press has arrived while buffered
+    released: Option<Duration>,
+}
+
+impl SequenceMatcher {
+    pub fn new(config: &Config) -> Self {
+        // Mapping keys with several whitespace separated button names are sequences
+        let sequences = config.mappings.iter()
+            .filter_map(|(key, mapping)| {
+                let buttons: Vec<String> = key.split_whitespace().map(str::to_string).collect();
+                if buttons.len() < 2 {
+                    return None;
+                }
+                mapping.tap.clone().map(|action| (buttons, action))
+            })
+            .collect();
+
+        Self {
+            sequences,
+            timeout: Duration::from_millis(config.sequence_timeout_ms),
+            policy: config.sequence_policy,
+            buffer: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    pub fn press(&mut self, button: &'static str, now: Instant) -> Vec<SequenceOutput> {
+        // Fast path: buttons that cannot start a sequence are never delayed
+        if self.buffer.is_empty() && !self.sequences.iter().any(|(seq, _)| seq[0] == button) {
+            return vec![SequenceOutput::Press(button)];
+        }
+
+        self.buffer.push(Buffered { button, released: None });
+        self.resolve(now, false)
+    }
+
+    pub fn release(&mut self, button: &'static str, duration: Duration) -> Vec<SequenceOutput> {
+        if let Some(entry) = self.buffer.iter_mut().rev().find(|b| b.button == button && b.released.is_none()) {
+            entry.released = Some(duration);
+            return Vec::new();
+        }
+        vec![SequenceOutput::Release(button, duration)]
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Resolve the buffered presses once the sequence timeout has expired
+    pub fn tick(&mut self, now: Instant) -> Vec<SequenceOutput> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => self.resolve(now, true),
+            _ => Vec::new(),
+        }
+    }
+
+    fn resolve(&mut self, now: Instant, timed_out: bool) -> Vec<SequenceOutput> {
+        let mut out = Vec::new();
+        self.deadline = None;
+
+        while !self.buffer.is_empty() {
+            let names: Vec<&str> = self.buffer.iter().map(|b| b.button).collect();
+            let exact = self.sequence_action(&names);
+            let extendable = self.sequences.iter()
+                .any(|(seq, _)| seq.len() > names.len() && seq.iter().zip(&names).all(|(a, b)| a == b));
+
+            if extendable && !timed_out {
+                if let (Some(action), SequencePolicy::Eager) = (&exact, self.policy) {
+                    debug!("Sequence '{}' matched", names.join(" "));
+                    out.push(SequenceOutput::Action(action.clone()));
+                    self.buffer.clear();
+                } else {
+                    // Wait for a longer match
+                    self.deadline = Some(now + self.timeout);
+                }
+                break;
+            }
+
+            if let Some(action) = exact {
+                debug!("Sequence '{}' matched", names.join(" "));
+                out.push(SequenceOutput::Action(action));
+                self.buffer.clear();
+                break;
+            }
+
+            // No sequence can complete any more: take the longest sequence at the start of the
+            // buffer, or fall back to a single-button press for the first buffered button
+            let longest = (2..names.len()).rev()
+                .find_map(|n| self.sequence_action(&names[..n]).map(|action| (n, action)));
+            match longest {
+                Some((n, action)) => {
+                    debug!("Sequence '{}' matched", names[..n].join(" "));
+                    out.push(SequenceOutput::Action(action));
+                    self.buffer.drain(..n);
+                }
+                None => {
+                    let first = self.buffer.remove(0);
+                    debug!("No sequence for '{}', falling back to single button", names.join(" "));
+                    out.push(SequenceOutput::Press(first.button));
+                    if let Some(duration) = first.released {
+                        out.push(SequenceOutput::Release(first.button, duration));
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    fn sequence_action(&self, names: &[&str]) -> Option<Action> {
+        self.sequences.iter()
+            .find(|(seq, _)| seq.len() == names.len() && seq.iter().zip(names).all(|(a, b)| a == b))
+            .map(|(_, action)| action.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(policy: &str) -> SequenceMatcher {
+        let yaml = format!("device_name: test\nsequence_timeout_ms: 500\nsequence_policy: {}\nmappings:\n  \
+            Number1 Number2: a\n  Number1 Number2 Number3: b\n  Up Down: c\n  Number1: \"1\"\n", policy);
+        let mut config: Config = serde_yaml_ng::from_str(&yaml).unwrap();
+        config.parse_mappings().unwrap();
+        SequenceMatcher::new(&config)
+    }
+
+    // Outputs as short strings: the action, +button for presses and -button for releases
+    fn names(outputs: Vec<SequenceOutput>) -> Vec<String> {
+        outputs.into_iter().map(|output| match output {
+            SequenceOutput::Action(action) => action.to_string(),
+            SequenceOutput::Press(button) => format!("+{}", button),
+            SequenceOutput::Release(button, _) => format!("-{}", button),
+        }).collect()
+    }
+
+    #[test]
+    fn longest_match_wins() {
+        let mut m = matcher("longest");
+        let now = Instant::now();
+        assert!(m.press("Number1", now).is_empty());
+        assert!(m.press("Number2", now).is_empty());
+        assert_eq!(m.next_deadline(), Some(now + Duration::from_millis(500)));
+        assert_eq!(names(m.press("Number3", now)), ["b"]);
+        assert_eq!(m.next_deadline(), None);
+
+        // The shorter sequence completes once the timeout expires without a longer match
+        assert!(m.press("Number1", now).is_empty());
+        assert!(m.press("Number2", now).is_empty());
+        assert!(m.tick(now + Duration::from_millis(499)).is_empty());
+        assert_eq!(names(m.tick(now + Duration::from_millis(500))), ["a"]);
+    }
+
+    #[test]
+    fn eager_sends_shorter_sequence() {
+        let mut m = matcher("eager");
+        let now = Instant::now();
+        assert!(m.press("Number1", now).is_empty());
+        assert_eq!(names(m.press("Number2", now)), ["a"]);
+        assert_eq!(names(m.press("Number3", now)), ["+Number3"]);
+    }
+
+    #[test]
+    fn unmatched_presses_fall_back() {
+        let mut m = matcher("longest");
+        let now = Instant::now();
+        assert_eq!(names(m.press("Left", now)), ["+Left"]);
+        assert_eq!(names(m.release("Left", Duration::ZERO)), ["-Left"]);
+
+        // A buffered press and its release are replayed in order, then the next press is matched
+        assert!(m.press("Number1", now).is_empty());
+        assert!(m.release("Number1", Duration::from_millis(80)).is_empty());
+        assert_eq!(names(m.press("Up", now)), ["+Number1", "-Number1"]);
+        assert_eq!(names(m.press("Down", now)), ["c"]);
+        assert!(m.press("Up", now).is_empty());
+        assert_eq!(names(m.tick(now + Duration::from_secs(1))), ["+Up"]);
+
+        // The longest sequence at the start of the buffer is taken before falling back
+        assert!(m.press("Number1", now).is_empty());
+        assert!(m.press("Number2", now).is_empty());
+        assert_eq!(names(m.press("Number5", now)), ["a", "+Number5"]);
+    }
+}
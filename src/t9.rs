@@ -0,0 +1,160 @@
+use crate::config::T9Config;
+use std::time::{Duration, Instant};
+use log::debug;
+
+/// Phone keypad style multi-tap text entry on the number buttons.
+///
+/// Repeated taps of a button cycle through its letter group; the letter is committed when the
+/// timeout expires or another button is pressed. Committed characters are returned to the
+/// caller, which types them on the virtual keyboard.
+pub struct T9 {
+    config: T9Config,
+    active: bool,
+    caps: bool,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    button: &'static str,
+    index: usize,
+    deadline: Instant,
+}
+
+/// Text produced by a T9 key press
+#[derive(Debug, PartialEq)]
+pub enum T9Output {
+    Char(char),
+    Backspace,
+}
+
+impl T9 {
+    pub fn new(config: T9Config) -> Self {
+        Self { config, active: false, caps: false, pending: None }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Enable or disable text entry, committing any pending letter when disabled
+    pub fn set_active(&mut self, active: bool) -> Option<char> {
+        self.active = active;
+        if active { None } else { self.commit() }
+    }
+
+    /// Handle a press while text entry is active.
+    /// Returns None for buttons that are not part of the keypad; the caller should `commit` first.
+    pub fn press(&mut self, button: &'static str, now: Instant) -> Option<Vec<T9Output>> {
+        let mut out = Vec::new();
+
+        if self.config.caps.as_deref() == Some(button) {
+            out.extend(self.commit().map(T9Output::Char));
+            self.caps = !self.caps;
+            debug!("T9 caps {}", if self.caps { "on" } else { "off" });
+            return Some(out);
+        }
+
+        if self.config.backspace.as_deref() == Some(button) {
+            // Backspace first cancels the letter being selected, then deletes typed text
+            if self.pending.take().is_none() {
+                out.push(T9Output::Backspace);
+            }
+            return Some(out);
+        }
+
+        let group_len = self.config.groups.get(button).map(|g| g.chars().count()).filter(|n| *n > 0)?;
+
+        let deadline = now + Duration::from_millis(self.config.timeout_ms);
+        match &mut self.pending {
+            Some(pending) if pending.button == button => {
+                pending.index = (pending.index + 1) % group_len;
+                pending.deadline = deadline;
+            }
+            _ => {
+                out.extend(self.commit().map(T9Output::Char));
+                self.pending = Some(Pending { button, index: 0, deadline });
+            }
+        }
+        debug!("T9 selecting '{}'", self.pending_char().unwrap_or(' '));
+        Some(out)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|p| p.deadline)
+    }
+
+    /// Commit the pending letter once its timeout has expired
+    pub fn tick(&mut self, now: Instant) -> Option<char> {
+        match &self.pending {
+            Some(pending) if pending.deadline <= now => self.commit(),
+            _ => None,
+        }
+    }
+
+    /// Commit the letter currently being selected
+    pub fn commit(&mut self) -> Option<char> {
+        let c = self.pending_char();
+        self.pending = None;
+        c
+    }
+
+    fn pending_char(&self) -> Option<char> {
+        let pending = self.pending.as_ref()?;
+        let c = self.config.groups.get(pending.button)?.chars().nth(pending.index)?;
+        Some(if self.caps { c.to_uppercase().next().unwrap_or(c) } else { c })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t9() -> T9 {
+        let mut t9 = T9::new(T9Config::default());
+        assert_eq!(t9.set_active(true), None);
+        t9
+    }
+
+    #[test]
+    fn multi_tap_commits_after_timeout() {
+        let mut t9 = t9();
+        let now = Instant::now();
+        assert_eq!(t9.press("Number2", now), Some(vec![]));
+        let later = now + Duration::from_millis(600);
+        assert_eq!(t9.press("Number2", later), Some(vec![]));
+        // Every tap restarts the timeout
+        assert_eq!(t9.next_deadline(), Some(later + Duration::from_millis(1000)));
+        assert_eq!(t9.tick(now + Duration::from_millis(1000)), None);
+        assert_eq!(t9.tick(later + Duration::from_millis(1000)), Some('b'));
+        assert_eq!(t9.next_deadline(), None);
+
+        // Taps cycle through the whole group and wrap around
+        for _ in 0..6 {
+            t9.press("Number9", now);
+        }
+        assert_eq!(t9.commit(), Some('w'));
+    }
+
+    #[test]
+    fn other_buttons_commit() {
+        let mut t9 = t9();
+        let now = Instant::now();
+        t9.press("Number4", now);
+        t9.press("Number4", now);
+        assert_eq!(t9.press("Number6", now), Some(vec![T9Output::Char('h')]));
+        assert_eq!(t9.press("Dot", now), Some(vec![T9Output::Char('m')]));
+        t9.press("Number3", now);
+        assert_eq!(t9.press("Up", now), None);
+        assert_eq!(t9.set_active(false), Some('D'));
+    }
+
+    #[test]
+    fn backspace_cancels_first() {
+        let mut t9 = t9();
+        let now = Instant::now();
+        t9.press("Number5", now);
+        assert_eq!(t9.press("Clear", now), Some(vec![]));
+        assert_eq!(t9.commit(), None);
+        assert_eq!(t9.press("Clear", now), Some(vec![T9Output::Backspace]));
+    }
+}
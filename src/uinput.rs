@@ -0,0 +1,246 @@
+//! A uinput device of our own instead of mouse_keyboard_input's `VirtualDevice`.
+//!
+//! That device declares a fixed set of keys when it is created, and uinput drops events for
+//! codes a device did not declare, so code()/key() actions for vendor keys beyond that set
+//! would be lost. It also has no high-resolution scroll axes and no absolute pointer. This
+//! module talks to /dev/uinput with the legacy `uinput_user_dev` setup, which every kernel
+//! with uinput supports.
+
+use nix::{ioctl_none, ioctl_write_int};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+// Event types and codes from linux/input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const REL_WHEEL_HI_RES: u16 = 0x0b;
+const REL_HWHEEL_HI_RES: u16 = 0x0c;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BUS_VIRTUAL: u16 = 0x06;
+
+// Highest key or button code the kernel accepts (KEY_MAX)
+pub const KEY_MAX: u16 = 0x2ff;
+
+// High-resolution wheel units per notch
+pub const WHEEL_NOTCH: i32 = 120;
+
+// Resolution of the absolute pointer axes, spanning the whole screen
+const ABS_RANGE: i32 = 32767;
+
+// ioctl requests from linux/uinput.h: _IO(UINPUT_IOCTL_BASE, 1) and so on
+const UINPUT_IOCTL_BASE: u8 = b'U';
+ioctl_none!(ui_dev_create, UINPUT_IOCTL_BASE, 1);
+ioctl_none!(ui_dev_destroy, UINPUT_IOCTL_BASE, 2);
+ioctl_write_int!(ui_set_evbit, UINPUT_IOCTL_BASE, 100);
+ioctl_write_int!(ui_set_keybit, UINPUT_IOCTL_BASE, 101);
+ioctl_write_int!(ui_set_relbit, UINPUT_IOCTL_BASE, 102);
+ioctl_write_int!(ui_set_absbit, UINPUT_IOCTL_BASE, 103);
+
+// One of the UI_SET_*BIT requests above
+type SetBit = unsafe fn(libc::c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<libc::c_int>;
+
+/// A uinput keyboard and mouse, or an absolute pointer.
+///
+/// uinput drops events for codes the device did not declare when it was created, so every key
+/// and button the mappings can send has to be passed to `new`.
+pub struct VirtualDevice {
+    file: File,
+    hi_res: bool,
+    // High-resolution scroll not yet sent as a whole notch, horizontal and vertical
+    wheel_remainder: (i32, i32),
+}
+
+impl VirtualDevice {
+    /// Create a device named `name` with the key and button `codes`, pointer motion and scroll
+    /// wheels, optionally with high-resolution scrolling
+    pub fn new(name: &str, codes: impl IntoIterator<Item = u16>, hi_res: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+
+        set_bit(&file, ui_set_evbit, EV_KEY)?;
+        for code in codes {
+            set_bit(&file, ui_set_keybit, code)?;
+        }
+        set_bit(&file, ui_set_evbit, EV_REL)?;
+        for rel in [REL_X, REL_Y, REL_WHEEL, REL_HWHEEL] {
+            set_bit(&file, ui_set_relbit, rel)?;
+        }
+        if hi_res {
+            set_bit(&file, ui_set_relbit, REL_WHEEL_HI_RES)?;
+            set_bit(&file, ui_set_relbit, REL_HWHEEL_HI_RES)?;
+        }
+
+        // SAFETY: uinput_user_dev is plain data, all zeroes is a valid value
+        let setup: libc::uinput_user_dev = unsafe { mem::zeroed() };
+        create(&file, name, 0xcec0, setup)?;
+        Ok(Self { file, hi_res, wheel_remainder: (0, 0) })
+    }
+
+    /// Create an absolute pointer named `name` for `pointer_to`.
+    ///
+    /// Kept apart from the relative mouse, which desktops would otherwise take for a tablet.
+    /// The mouse buttons make it a pointing device rather than a touchscreen.
+    pub fn absolute(name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+
+        set_bit(&file, ui_set_evbit, EV_KEY)?;
+        for code in [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE] {
+            set_bit(&file, ui_set_keybit, code)?;
+        }
+        set_bit(&file, ui_set_evbit, EV_ABS)?;
+        // SAFETY: uinput_user_dev is plain data, all zeroes is a valid value
+        let mut setup: libc::uinput_user_dev = unsafe { mem::zeroed() };
+        for axis in [ABS_X, ABS_Y] {
+            set_bit(&file, ui_set_absbit, axis)?;
+            setup.absmax[usize::from(axis)] = ABS_RANGE;
+        }
+
+        create(&file, name, 0xcec1, setup)?;
+        Ok(Self { file, hi_res: false, wheel_remainder: (0, 0) })
+    }
+
+    pub fn press(&mut self, code: u16) -> io::Result<()> {
+        self.emit(EV_KEY, code, 1)?;
+        self.sync()
+    }
+
+    pub fn release(&mut self, code: u16) -> io::Result<()> {
+        self.emit(EV_KEY, code, 0)?;
+        self.sync()
+    }
+
+    /// Move the pointer by a relative amount, positive y moving up
+    pub fn move_mouse(&mut self, x: i32, y: i32) -> io::Result<()> {
+        if x != 0 {
+            self.emit(EV_REL, REL_X, x)?;
+        }
+        if y != 0 {
+            self.emit(EV_REL, REL_Y, -y)?;
+        }
+        self.sync()
+    }
+
+    /// Move the pointer to a position given as fractions of the screen, from the top left corner
+    pub fn move_to(&mut self, x: f64, y: f64) -> io::Result<()> {
+        let scale = |v: f64| (v.clamp(0.0, 1.0) * f64::from(ABS_RANGE)).round() as i32;
+        self.emit(EV_ABS, ABS_X, scale(x))?;
+        self.emit(EV_ABS, ABS_Y, scale(y))?;
+        self.sync()
+    }
+
+    /// Scroll by `x` and `y` high-resolution units (1/120 of a notch), positive x scrolling right
+    /// and positive y scrolling up. Whole notches are sent on the classic wheel axes once enough
+    /// units have built up, for applications that do not read high-resolution events.
+    pub fn scroll(&mut self, x: i32, y: i32) -> io::Result<()> {
+        let (rx, ry) = (self.wheel_remainder.0 + x, self.wheel_remainder.1 + y);
+        self.wheel_remainder = (rx % WHEEL_NOTCH, ry % WHEEL_NOTCH);
+        let (notches_x, notches_y) = (rx / WHEEL_NOTCH, ry / WHEEL_NOTCH);
+
+        if self.hi_res {
+            if x != 0 {
+                self.emit(EV_REL, REL_HWHEEL_HI_RES, x)?;
+            }
+            if y != 0 {
+                self.emit(EV_REL, REL_WHEEL_HI_RES, y)?;
+            }
+        }
+        if notches_x != 0 {
+            self.emit(EV_REL, REL_HWHEEL, notches_x)?;
+        }
+        if notches_y != 0 {
+            self.emit(EV_REL, REL_WHEEL, notches_y)?;
+        }
+        self.sync()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.emit(EV_SYN, SYN_REPORT, 0)
+    }
+
+    fn emit(&mut self, kind: u16, code: u16, value: i32) -> io::Result<()> {
+        // SAFETY: input_event is plain data; the kernel fills in the timestamp
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = kind;
+        event.code = code;
+        event.value = value;
+        self.file.write_all(as_bytes(&event))
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        // SAFETY: UI_DEV_DESTROY takes no argument and the file is an open uinput device
+        let _ = unsafe { ui_dev_destroy(self.file.as_raw_fd()) };
+    }
+}
+
+// Set the name and id of a configured device and create it
+fn create(file: &File, name: &str, product: u16, mut setup: libc::uinput_user_dev) -> io::Result<()> {
+    for (dst, src) in setup.name.iter_mut().zip(name.bytes().take(libc::UINPUT_MAX_NAME_SIZE - 1)) {
+        *dst = src as libc::c_char;
+    }
+    setup.id = libc::input_id { bustype: BUS_VIRTUAL, vendor: 0x1209, product, version: 1 };
+    let mut writer = file;
+    writer.write_all(as_bytes(&setup))?;
+    // SAFETY: UI_DEV_CREATE takes no argument and the file is an open uinput device
+    unsafe { ui_dev_create(file.as_raw_fd()) }?;
+    Ok(())
+}
+
+fn set_bit(file: &File, request: SetBit, code: u16) -> io::Result<()> {
+    // SAFETY: the UI_SET_*BIT requests take the code as a plain integer argument
+    unsafe { request(file.as_raw_fd(), code.into()) }?;
+    Ok(())
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    // SAFETY: only used with the plain-data uinput structs above
+    unsafe { std::slice::from_raw_parts((value as *const T).cast::<u8>(), mem::size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::offset_of;
+
+    #[test]
+    fn uinput_user_dev_layout() {
+        // struct uinput_user_dev from linux/uinput.h, written to the device as raw bytes
+        assert_eq!(offset_of!(libc::uinput_user_dev, name), 0);
+        assert_eq!(offset_of!(libc::uinput_user_dev, id), libc::UINPUT_MAX_NAME_SIZE);
+        assert_eq!(offset_of!(libc::uinput_user_dev, ff_effects_max), 88);
+        assert_eq!(offset_of!(libc::uinput_user_dev, absmax), 92);
+        assert_eq!(mem::size_of::<libc::uinput_user_dev>(), 92 + 4 * 64 * 4);
+        assert_eq!(mem::size_of::<libc::input_id>(), 8);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64", target_arch = "arm"))]
+    fn request_codes() {
+        // The values strace shows for these requests on the common architectures
+        assert_eq!(nix::request_code_none!(UINPUT_IOCTL_BASE, 1), 0x5501);
+        assert_eq!(nix::request_code_none!(UINPUT_IOCTL_BASE, 2), 0x5502);
+        let int = mem::size_of::<libc::c_int>();
+        assert_eq!(nix::request_code_write!(UINPUT_IOCTL_BASE, 100, int), 0x4004_5564);
+        assert_eq!(nix::request_code_write!(UINPUT_IOCTL_BASE, 103, int), 0x4004_5567);
+    }
+}